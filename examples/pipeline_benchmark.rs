@@ -0,0 +1,110 @@
+// Measures SET and GET throughput against a running server, sending one command at a time and
+// then in pipelines, so the effect of batching replies per connection shows up.
+//
+// cargo run --release --example pipeline_benchmark -- [address] [requests] [pipeline depth]
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
+    let mut args = std::env::args().skip(1);
+    let address = args.next().unwrap_or(String::from("127.0.0.1:6379"));
+    let requests: usize = match args.next() {
+        Some(x) => x.parse()?,
+        None => 100_000,
+    };
+    let depth: usize = match args.next() {
+        Some(x) => x.parse()?,
+        None => 100,
+    };
+
+    let mut stream = TcpStream::connect(&address).await?;
+    for (name, pipeline_depth) in [("no pipelining", 1), ("pipelined", depth)] {
+        for command in ["SET", "GET"] {
+            let start = Instant::now();
+            run(&mut stream, command, requests, pipeline_depth).await?;
+            let elapsed = start.elapsed();
+            println!(
+                "{} {} ({} per batch): {} requests in {:.2?}, {:.0} requests per second",
+                command,
+                name,
+                pipeline_depth,
+                requests,
+                elapsed,
+                requests as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
+    Ok(())
+}
+
+// Sends the requests in batches of pipeline_depth, reading every reply of a batch before the next
+async fn run(
+    stream: &mut TcpStream,
+    command: &str,
+    requests: usize,
+    pipeline_depth: usize,
+) -> std::io::Result<()> {
+    let mut buffer = Vec::new();
+    let mut sent = 0;
+    while sent < requests {
+        let batch = pipeline_depth.min(requests - sent);
+        let mut request = Vec::new();
+        for i in sent..sent + batch {
+            let key = format!("key:{}", i % 10_000);
+            match command {
+                "SET" => request.extend(encode(&["SET", &key, "value"])),
+                _ => request.extend(encode(&["GET", &key])),
+            }
+        }
+        stream.write_all(&request).await?;
+        let mut replies = 0;
+        while replies < batch {
+            match reply_length(&buffer) {
+                Some(length) => {
+                    buffer.drain(..length);
+                    replies += 1;
+                }
+                None => {
+                    let mut chunk = [0; 16 * 1024];
+                    let n = stream.read(&mut chunk).await?;
+                    if n == 0 {
+                        return Err(std::io::ErrorKind::UnexpectedEof.into());
+                    }
+                    buffer.extend_from_slice(&chunk[..n]);
+                }
+            }
+        }
+        sent += batch;
+    }
+    Ok(())
+}
+
+fn encode(args: &[&str]) -> Vec<u8> {
+    let mut data = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        data.extend(format!("${}\r\n{}\r\n", arg.len(), arg).into_bytes());
+    }
+    data
+}
+
+// Length of the reply at the start of data, None if it hasn't fully arrived. Only the simple
+// replies SET and GET send are understood
+fn reply_length(data: &[u8]) -> Option<usize> {
+    let crlf_index = data.windows(2).position(|x| x == b"\r\n")?;
+    match data[0] {
+        b'$' => {
+            let length: i64 = std::str::from_utf8(&data[1..crlf_index])
+                .ok()?
+                .parse()
+                .ok()?;
+            if length < 0 {
+                return Some(crlf_index + 2);
+            }
+            let end = crlf_index + 2 + length as usize + 2;
+            (data.len() >= end).then_some(end)
+        }
+        _ => Some(crlf_index + 2),
+    }
+}
@@ -1,34 +1,95 @@
+use crate::redis::aof::FsyncPolicy;
 use crate::redis::RedisState;
 use std::{env, path::PathBuf};
 
 pub struct Config {
     pub port: String,
     pub role: RedisState,
-    pub master_replid: Option<String>,
-    pub master_repl_offset: Option<String>,
     pub master_port: Option<String>,
     pub master_host: Option<String>,
     pub rdb_dir: Option<PathBuf>,
     pub rdb_filename: Option<PathBuf>,
+    pub append_only: bool,
+    pub aof_filename: Option<PathBuf>,
+    pub aof_fsync: FsyncPolicy,
+    pub aof_use_rdb_preamble: bool,
+    pub repl_backlog_size: usize,
+    // Bytes a connection may have waiting to be written before it is dropped, 0 for no limit
+    pub client_output_buffer_limit: usize,
+    // Seconds without any traffic before a replication link is considered dead
+    pub repl_timeout: u64,
+    // Seconds between the PINGs a master sends down the replication stream
+    pub repl_ping_replica_period: u64,
+    // Full resyncs stream the RDB straight into the replica sockets, starting
+    // repl_diskless_sync_delay seconds after the first replica asks so others can share it
+    pub repl_diskless_sync: bool,
+    pub repl_diskless_sync_delay: u64,
+    // Replicas elect a new master among themselves once it's been unreachable for
+    // failover_timeout milliseconds
+    pub auto_failover: bool,
+    pub failover_timeout: u64,
+    // Writes are refused unless at least min_replicas_to_write replicas acknowledged within the
+    // last min_replicas_max_lag seconds, 0 turns the check off
+    pub min_replicas_to_write: usize,
+    pub min_replicas_max_lag: u64,
+    // Replicas reject writes from their own clients
+    pub replica_read_only: bool,
+    // Run as a sentinel watching the master named in sentinel_monitor, see sentinel.rs
+    pub sentinel: bool,
+    pub sentinel_monitor: Option<SentinelMonitor>,
+    pub sentinel_peers: Vec<(String, String)>,
+    // Milliseconds without a reply before a sentinel considers an instance down
+    pub sentinel_down_after: u64,
+}
+
+pub struct SentinelMonitor {
+    pub name: String,
+    pub host: String,
+    pub port: String,
+    // Sentinels that have to agree the master is down before a failover starts
+    pub quorum: usize,
 }
 
 enum ConfigParseError {
     NoArgFound,
 }
 
-impl Config {
-    pub fn parse() -> Self {
-        let args: Vec<String> = env::args().collect();
-        let mut config = Config {
+impl Default for Config {
+    fn default() -> Self {
+        Config {
             port: String::from("6379"),
             role: RedisState::Master,
-            master_replid: None,
-            master_repl_offset: None,
             master_port: None,
             master_host: None,
             rdb_dir: None,
             rdb_filename: None,
-        };
+            append_only: false,
+            aof_filename: None,
+            aof_fsync: FsyncPolicy::EverySec,
+            aof_use_rdb_preamble: true,
+            repl_backlog_size: 1024 * 1024,
+            client_output_buffer_limit: 256 * 1024 * 1024,
+            repl_timeout: 60,
+            repl_ping_replica_period: 10,
+            repl_diskless_sync: false,
+            repl_diskless_sync_delay: 5,
+            auto_failover: false,
+            failover_timeout: 5000,
+            min_replicas_to_write: 0,
+            min_replicas_max_lag: 10,
+            replica_read_only: true,
+            sentinel: false,
+            sentinel_monitor: None,
+            sentinel_peers: vec![],
+            sentinel_down_after: 5000,
+        }
+    }
+}
+
+impl Config {
+    pub fn parse() -> Self {
+        let args: Vec<String> = env::args().collect();
+        let mut config = Config::default();
         let mut index = 0;
         while index < args.len() {
             match args[index].as_str() {
@@ -65,19 +126,236 @@ impl Config {
                         panic!("Error: --dbfilename requires a value");
                     }
                 },
+                "--appendonly" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => config.append_only = parse_yes_no(&x, "--appendonly"),
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --appendonly requires a value");
+                    }
+                },
+                "--appendfilename" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => config.aof_filename = Some(PathBuf::from(x)),
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --appendfilename requires a value");
+                    }
+                },
+                "--aof-use-rdb-preamble" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => {
+                        config.aof_use_rdb_preamble = parse_yes_no(&x, "--aof-use-rdb-preamble")
+                    }
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --aof-use-rdb-preamble requires a value");
+                    }
+                },
+                "--repl-backlog-size" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => config.repl_backlog_size = parse_memory(&x, "--repl-backlog-size"),
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --repl-backlog-size requires a value");
+                    }
+                },
+                "--client-output-buffer-limit" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => {
+                        config.client_output_buffer_limit =
+                            parse_memory(&x, "--client-output-buffer-limit")
+                    }
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --client-output-buffer-limit requires a value");
+                    }
+                },
+                "--repl-timeout" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => config.repl_timeout = parse_positive(&x, "--repl-timeout"),
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --repl-timeout requires a value");
+                    }
+                },
+                "--repl-ping-replica-period" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => {
+                        config.repl_ping_replica_period =
+                            parse_positive(&x, "--repl-ping-replica-period")
+                    }
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --repl-ping-replica-period requires a value");
+                    }
+                },
+                "--repl-diskless-sync" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => config.repl_diskless_sync = parse_yes_no(&x, "--repl-diskless-sync"),
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --repl-diskless-sync requires a value");
+                    }
+                },
+                "--repl-diskless-sync-delay" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => match x.parse::<u64>() {
+                        Ok(x) => config.repl_diskless_sync_delay = x,
+                        Err(_) => panic!("Error: --repl-diskless-sync-delay requires a number"),
+                    },
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --repl-diskless-sync-delay requires a value");
+                    }
+                },
+                "--auto-failover" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => config.auto_failover = parse_yes_no(&x, "--auto-failover"),
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --auto-failover requires a value");
+                    }
+                },
+                "--failover-timeout" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => config.failover_timeout = parse_positive(&x, "--failover-timeout"),
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --failover-timeout requires a value");
+                    }
+                },
+                "--min-replicas-to-write" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => match x.parse::<usize>() {
+                        Ok(x) => config.min_replicas_to_write = x,
+                        Err(_) => panic!("Error: --min-replicas-to-write requires a number"),
+                    },
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --min-replicas-to-write requires a value");
+                    }
+                },
+                "--min-replicas-max-lag" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => match x.parse::<u64>() {
+                        Ok(x) => config.min_replicas_max_lag = x,
+                        Err(_) => panic!("Error: --min-replicas-max-lag requires a number"),
+                    },
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --min-replicas-max-lag requires a value");
+                    }
+                },
+                "--replica-read-only" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => config.replica_read_only = parse_yes_no(&x, "--replica-read-only"),
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --replica-read-only requires a value");
+                    }
+                },
+                "--sentinel" => config.sentinel = true,
+                "--sentinel-monitor" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => {
+                        let parts: Vec<&str> = x.split(' ').collect();
+                        if parts.len() != 4 {
+                            panic!(
+                                "Error: --sentinel-monitor requires a name, host, port and quorum"
+                            );
+                        }
+                        config.sentinel_monitor = Some(SentinelMonitor {
+                            name: parts[0].to_string(),
+                            host: parts[1].to_string(),
+                            port: parts[2].to_string(),
+                            quorum: parse_positive(parts[3], "--sentinel-monitor quorum") as usize,
+                        });
+                    }
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --sentinel-monitor requires four values");
+                    }
+                },
+                "--sentinel-peers" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => {
+                        for peer in x.split(',').filter(|x| !x.is_empty()) {
+                            match peer.split_once(':') {
+                                Some((host, port)) => config
+                                    .sentinel_peers
+                                    .push((host.to_string(), port.to_string())),
+                                None => panic!("Error: --sentinel-peers expects host:port pairs"),
+                            }
+                        }
+                    }
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --sentinel-peers requires a value");
+                    }
+                },
+                "--sentinel-down-after" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => {
+                        config.sentinel_down_after = parse_positive(&x, "--sentinel-down-after")
+                    }
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --sentinel-down-after requires a value");
+                    }
+                },
+                "--appendfsync" => match read_next_arg(&args, &mut index) {
+                    Ok(x) => {
+                        config.aof_fsync = match x.to_lowercase().as_str() {
+                            "always" => FsyncPolicy::Always,
+                            "everysec" => FsyncPolicy::EverySec,
+                            "no" => FsyncPolicy::No,
+                            other => panic!("Error: unsupported --appendfsync policy {}", other),
+                        }
+                    }
+                    Err(ConfigParseError::NoArgFound) => {
+                        panic!("Error: --appendfsync requires a value");
+                    }
+                },
                 _ => {}
             }
             index += 1; // Move to the next argument
         }
-        if config.role == RedisState::Master {
-            config.master_replid = Some(String::from("8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb"));
-            config.master_repl_offset = Some(String::from("0"));
-        }
         config
     }
 }
 
-fn read_next_arg(args: &Vec<String>, curr_index: &mut usize) -> Result<String, ConfigParseError> {
+impl Config {
+    // Falls back to dump.rdb in the working directory, matching Redis' defaults
+    pub fn rdb_path(&self) -> PathBuf {
+        let mut path = self.rdb_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+        path.push(
+            self.rdb_filename
+                .clone()
+                .unwrap_or_else(|| PathBuf::from("dump.rdb")),
+        );
+        path
+    }
+
+    // The AOF lives alongside the RDB file in --dir
+    pub fn aof_path(&self) -> PathBuf {
+        let mut path = self.rdb_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+        path.push(
+            self.aof_filename
+                .clone()
+                .unwrap_or_else(|| PathBuf::from("appendonly.aof")),
+        );
+        path
+    }
+}
+
+// Accepts plain byte counts as well as Redis style units such as 64kb or 1mb
+fn parse_memory(value: &str, arg: &str) -> usize {
+    let value = value.to_lowercase();
+    let (digits, multiplier) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => {
+            let multiplier = match &value[index..] {
+                "b" => 1,
+                "k" => 1000,
+                "kb" => 1024,
+                "m" => 1000 * 1000,
+                "mb" => 1024 * 1024,
+                "g" => 1000 * 1000 * 1000,
+                "gb" => 1024 * 1024 * 1024,
+                _ => panic!("Error: {} has an unknown unit", arg),
+            };
+            (&value[..index], multiplier)
+        }
+        None => (value.as_str(), 1),
+    };
+    match digits.parse::<usize>() {
+        Ok(x) => x * multiplier,
+        Err(_) => panic!("Error: {} requires a size in bytes", arg),
+    }
+}
+
+fn parse_positive(value: &str, arg: &str) -> u64 {
+    match value.parse::<u64>() {
+        Ok(x) if x > 0 => x,
+        _ => panic!("Error: {} requires a positive number", arg),
+    }
+}
+
+fn parse_yes_no(value: &str, arg: &str) -> bool {
+    match value.to_lowercase().as_str() {
+        "yes" => true,
+        "no" => false,
+        _ => panic!("Error: {} requires yes or no", arg),
+    }
+}
+
+fn read_next_arg(args: &[String], curr_index: &mut usize) -> Result<String, ConfigParseError> {
     if *curr_index + 1 >= args.len() {
         return Err(ConfigParseError::NoArgFound);
     }
@@ -3,18 +3,25 @@ use tokio::net::TcpListener;
 
 use crate::config::*;
 use crate::redis::Redis;
+use crate::sentinel::Sentinel;
 
 pub mod config;
 pub mod rdb;
 pub mod redis;
 pub mod resp;
+pub mod sentinel;
 
 #[tokio::main]
-async fn main() -> Result<(), Box<(dyn std::error::Error + 'static)>> {
+async fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
     let config = Arc::new(Config::parse());
     let host = String::from("127.0.0.1");
     let listener = TcpListener::bind(format!("{}:{}", host, &config.port)).await?;
-    let mut redis = Redis::new(Arc::clone(&config), listener).await.expect("");
+    if config.sentinel {
+        let mut sentinel = Sentinel::new(Arc::clone(&config), listener);
+        sentinel.listen().await?;
+        return Ok(());
+    }
+    let mut redis = Redis::new(Arc::clone(&config), listener).await?;
     redis.listen().await?;
     Ok(())
 }
@@ -1,98 +1,148 @@
+pub mod intset;
+pub mod listpack;
+pub mod rdb_parser;
+pub mod rdb_writer;
+pub mod ziplist;
+
+use crate::redis::keyspace::{Keyspace, SortedSet, Value};
+use crate::redis::quicklist::QuickList;
+
+use bytes::Bytes;
 use std::collections::HashMap;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::SystemTime;
+use thiserror::Error;
 
-pub struct RdbParser {
-    data: Vec<u8>,
-    index: usize,
-}
+// The keyspace and its expiry table as stored in an RDB file
+pub type Dataset = (Keyspace, HashMap<Bytes, SystemTime>);
 
-const EOF_FLAG: u8 = 0xff;
-const EXPIRY_MS_FLAG: u8 = 0xfc;
-const EXPIRY_S_FLAG: u8 = 0xfd;
+// Opcodes that can appear between key-value pairs
+pub const OPCODE_FUNCTION: u8 = 0xf5;
+pub const OPCODE_MODULE_AUX: u8 = 0xf7;
+pub const OPCODE_IDLE: u8 = 0xf8;
+pub const OPCODE_FREQ: u8 = 0xf9;
+pub const OPCODE_AUX: u8 = 0xfa;
+pub const OPCODE_RESIZE_DB: u8 = 0xfb;
+pub const OPCODE_EXPIRY_MS: u8 = 0xfc;
+pub const OPCODE_EXPIRY_S: u8 = 0xfd;
+pub const OPCODE_SELECT_DB: u8 = 0xfe;
+pub const OPCODE_EOF: u8 = 0xff;
 
-impl RdbParser {
-    // Public
-    pub fn new(data: Vec<u8>) -> Self {
-        Self { data, index: 0 }
-    }
+// Value types. Every one Redis writes up to RDB version 11 is loaded except for modules, streams
+// and the zipmap hashes of Redis 2.4, see value_type_name
+pub const TYPE_STRING: u8 = 0;
+pub const TYPE_LIST: u8 = 1;
+pub const TYPE_SET: u8 = 2;
+// Scores stored as strings, before RDB version 8
+pub const TYPE_ZSET: u8 = 3;
+pub const TYPE_HASH: u8 = 4;
+pub const TYPE_ZSET_2: u8 = 5;
+pub const TYPE_LIST_ZIPLIST: u8 = 10;
+pub const TYPE_SET_INTSET: u8 = 11;
+pub const TYPE_ZSET_ZIPLIST: u8 = 12;
+pub const TYPE_HASH_ZIPLIST: u8 = 13;
+// A list of nodes, each a ziplist of several elements, written by Redis 3.2 to 6.2
+pub const TYPE_LIST_QUICKLIST: u8 = 14;
+pub const TYPE_HASH_LISTPACK: u8 = 16;
+pub const TYPE_ZSET_LISTPACK: u8 = 17;
+// A list of nodes, each either a single plain element or a listpack of several
+pub const TYPE_LIST_QUICKLIST_2: u8 = 18;
+pub const TYPE_SET_LISTPACK: u8 = 20;
 
-    pub fn rdb_to_db(&mut self) -> (HashMap<String, String>, HashMap<String, SystemTime>) {
-        self.parse_header();
-        self.parse_metadata();
-        let mut database: HashMap<String, String> = HashMap::new();
-        let mut expiry: HashMap<String, SystemTime> = HashMap::new();
-        while self.data[self.index] != EOF_FLAG {
-            println!("Reading KEY-Value");
-            let (expiration, key, value) = self.parse_key_value();
-            if let Some(x) = expiration {
-                expiry.insert(key.clone(), x);
-            }
-            database.insert(key, value);
-        }
-        (database, expiry)
-    }
+// Scores of a TYPE_ZSET are prefixed by their length, with these lengths standing for the values
+pub const ZSET_SCORE_NAN: u8 = 253;
+pub const ZSET_SCORE_POS_INF: u8 = 254;
+pub const ZSET_SCORE_NEG_INF: u8 = 255;
 
-    // Private
-    fn parse_header(&mut self) {
-        let header = &self.data[0..9];
-        println!("Header: {:?}", header);
-        self.index += 9;
+// For the error naming a value type that can't be loaded
+pub fn value_type_name(value_type: u8) -> &'static str {
+    match value_type {
+        6 | 7 => "module",
+        9 => "zipmap hash",
+        15 | 19 | 21 => "stream",
+        _ => "unknown",
     }
+}
 
-    fn parse_metadata(&mut self) {
-        let hash_start_index = self.data.iter().position(|&b| b == 0xfb).unwrap();
-        self.index = hash_start_index;
-        // Skip over RESIZE DB Field
-        self.index += 3;
-    }
+// Container of each quicklist node
+pub const QUICKLIST_NODE_PLAIN: usize = 1;
+pub const QUICKLIST_NODE_PACKED: usize = 2;
 
-    fn parse_key_value(&mut self) -> (Option<SystemTime>, String, String) {
-        let expiry = self.parse_expiry();
-        // Skip over Value field
-        self.index += 1;
-        let length_key = self.data[self.index];
-        self.index += 1;
+// The two most significant bits of the first length byte select the encoding
+pub const LENGTH_6BIT: u8 = 0b00;
+pub const LENGTH_14BIT: u8 = 0b01;
+pub const LENGTH_32OR64BIT: u8 = 0b10;
+pub const LENGTH_SPECIAL: u8 = 0b11;
+pub const LENGTH_32BIT: u8 = 0x80;
+pub const LENGTH_64BIT: u8 = 0x81;
 
-        let key = &self.data[self.index..(self.index + length_key as usize)];
-        let key = std::str::from_utf8(key).unwrap();
-        self.index += length_key as usize;
+// Special string encodings, stored in the low six bits when the length type is LENGTH_SPECIAL
+pub const ENCODING_INT8: u8 = 0;
+pub const ENCODING_INT16: u8 = 1;
+pub const ENCODING_INT32: u8 = 2;
+pub const ENCODING_LZF: u8 = 3;
 
-        println!("Key Length: {}", length_key);
-        println!("Key: {}", key);
+pub const MAGIC: &[u8] = b"REDIS";
+pub const MAX_SUPPORTED_VERSION: u32 = 11;
+// Checksums were introduced with RDB version 5
+pub const MIN_CHECKSUM_VERSION: u32 = 5;
 
-        let length_value = self.data[self.index];
-        self.index += 1;
+#[derive(Error, Debug)]
+pub enum RdbError {
+    #[error("RDB file does not start with the REDIS magic string")]
+    InvalidHeader,
+    #[error("RDB version {0} is not supported")]
+    UnsupportedVersion(u32),
+    #[error("RDB file ended unexpectedly at byte {0}")]
+    UnexpectedEof(usize),
+    #[error("Unsupported RDB opcode: {0:#04x}")]
+    UnsupportedOpcode(u8),
+    #[error("Unsupported RDB value type {0} ({1})")]
+    UnsupportedValueType(u8, &'static str),
+    #[error("Corrupt value of RDB type {0}")]
+    InvalidValue(u8),
+    #[error("Invalid string encoding: {0}")]
+    InvalidEncoding(u8),
+    #[error("Corrupt LZF compressed string")]
+    InvalidLzf,
+    #[error("Corrupt listpack")]
+    InvalidListpack,
+    #[error("Corrupt ziplist")]
+    InvalidZiplist,
+    #[error("Corrupt intset")]
+    InvalidIntset,
+    #[error("Unsupported quicklist node container: {0}")]
+    UnsupportedQuicklistContainer(usize),
+    #[error("RDB checksum mismatch: file has {expected:#018x}, computed {computed:#018x}")]
+    ChecksumMismatch { expected: u64, computed: u64 },
+}
 
-        let value = &self.data[self.index..(self.index + length_value as usize)];
-        let value = std::str::from_utf8(value).unwrap();
-        self.index += length_value as usize;
+// CRC-64/Jones as used by Redis (reflected polynomial, zero init, no final xor)
+const CRC64_POLY: u64 = 0x95ac9329ac4bc9b5;
 
-        println!("Length of Value: {}", length_value);
-        println!("Value: {}", value);
-        if let Some(x) = expiry {
-            println!("Expiry: {:?}", x);
+const fn build_crc64_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC64_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
         }
-
-        (expiry, key.to_string(), value.to_string())
+        table[i] = crc;
+        i += 1;
     }
+    table
+}
 
-    fn parse_expiry(&mut self) -> Option<SystemTime> {
-        match self.data[self.index] {
-            EXPIRY_MS_FLAG => {
-                self.index += 1;
-                let bytes: [u8; 8] = self.data[self.index..self.index + 8].try_into().unwrap();
-                self.index += 8;
-                let millis = u64::from_le_bytes(bytes);
-                Some(UNIX_EPOCH + Duration::from_millis(millis))
-            }
-            EXPIRY_S_FLAG => {
-                self.index += 1;
-                let bytes: [u8; 4] = self.data[self.index..self.index + 4].try_into().unwrap();
-                self.index += 4;
-                let secs = u32::from_le_bytes(bytes) as u64;
-                Some(UNIX_EPOCH + Duration::from_secs(secs))
-            }
-            _ => None,
-        }
-    }
+const CRC64_TABLE: [u64; 256] = build_crc64_table();
+
+pub fn crc64(crc: u64, data: &[u8]) -> u64 {
+    data.iter().fold(crc, |crc, &byte| {
+        CRC64_TABLE[((crc ^ byte as u64) & 0xff) as usize] ^ (crc >> 8)
+    })
 }
@@ -0,0 +1,72 @@
+use super::RdbError;
+
+use bytes::Bytes;
+
+// Byte width of every member and the number of members, both little endian
+const HEADER_SIZE: usize = 8;
+
+// The sorted array of integers small sets of integers are stored as. Members come back as their
+// decimal representation, the way Redis replies with them
+pub fn decode(data: &[u8]) -> Result<Vec<Bytes>, RdbError> {
+    let header = data.get(..HEADER_SIZE).ok_or(RdbError::InvalidIntset)?;
+    let width = u32::from_le_bytes(header[..4].try_into().expect("Sliced 4 bytes")) as usize;
+    let length = u32::from_le_bytes(header[4..].try_into().expect("Sliced 4 bytes")) as usize;
+    if !matches!(width, 2 | 4 | 8) || data.len() != HEADER_SIZE + width * length {
+        return Err(RdbError::InvalidIntset);
+    }
+    let members = data[HEADER_SIZE..]
+        .chunks(width)
+        .map(|x| {
+            let value = match width {
+                2 => i16::from_le_bytes(x.try_into().expect("Chunk of 2 bytes")) as i64,
+                4 => i32::from_le_bytes(x.try_into().expect("Chunk of 4 bytes")) as i64,
+                _ => i64::from_le_bytes(x.try_into().expect("Chunk of 8 bytes")),
+            };
+            Bytes::from(value.to_string())
+        })
+        .collect();
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn intset(width: u32, members: &[i64]) -> Vec<u8> {
+        let mut data = width.to_le_bytes().to_vec();
+        data.extend_from_slice(&(members.len() as u32).to_le_bytes());
+        for member in members {
+            data.extend_from_slice(&member.to_le_bytes()[..width as usize]);
+        }
+        data
+    }
+
+    fn strings(members: &[Bytes]) -> Vec<String> {
+        members
+            .iter()
+            .map(|x| String::from_utf8_lossy(x).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn decodes_every_width() {
+        let decoded = decode(&intset(2, &[-32768, 1, 32767])).unwrap();
+        assert_eq!(strings(&decoded), ["-32768", "1", "32767"]);
+        let decoded = decode(&intset(4, &[-70000, 70000])).unwrap();
+        assert_eq!(strings(&decoded), ["-70000", "70000"]);
+        let decoded = decode(&intset(8, &[i64::MIN, i64::MAX])).unwrap();
+        assert_eq!(
+            strings(&decoded),
+            [i64::MIN.to_string(), i64::MAX.to_string()]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_intsets() {
+        assert!(decode(&intset(3, &[1])).is_err());
+        let mut truncated = intset(4, &[1, 2]);
+        truncated.pop();
+        assert!(decode(&truncated).is_err());
+        assert!(decode(&[2, 0]).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use super::RdbError;
+
+use bytes::Bytes;
+
+// Total byte count and number of entries, both little endian
+const HEADER_SIZE: usize = 6;
+const END: u8 = 0xff;
+// The entry count saturates, the entries then have to be walked to count them
+const UNKNOWN_COUNT: u16 = u16::MAX;
+
+// Entry encodings, identified by the high bits of the first byte
+const ENCODING_7BIT_UINT: u8 = 0x00;
+const ENCODING_6BIT_STR: u8 = 0x80;
+const ENCODING_13BIT_INT: u8 = 0xc0;
+const ENCODING_12BIT_STR: u8 = 0xe0;
+const ENCODING_32BIT_STR: u8 = 0xf0;
+const ENCODING_16BIT_INT: u8 = 0xf1;
+const ENCODING_24BIT_INT: u8 = 0xf2;
+const ENCODING_32BIT_INT: u8 = 0xf3;
+const ENCODING_64BIT_INT: u8 = 0xf4;
+
+// Packs the elements as strings, which Redis reads back whatever they contain
+pub fn encode<'a>(elements: impl IntoIterator<Item = &'a Bytes>) -> Vec<u8> {
+    let mut data = vec![0; HEADER_SIZE];
+    let mut count = 0usize;
+    for element in elements {
+        let start = data.len();
+        let length = element.len();
+        if length < 1 << 6 {
+            data.push(ENCODING_6BIT_STR | length as u8);
+        } else if length < 1 << 12 {
+            data.push(ENCODING_12BIT_STR | (length >> 8) as u8);
+            data.push(length as u8);
+        } else {
+            data.push(ENCODING_32BIT_STR);
+            data.extend_from_slice(&(length as u32).to_le_bytes());
+        }
+        data.extend_from_slice(element);
+        let entry_length = data.len() - start;
+        encode_backlen(entry_length, &mut data);
+        count += 1;
+    }
+    data.push(END);
+    let total = data.len() as u32;
+    let count = u16::try_from(count).unwrap_or(UNKNOWN_COUNT);
+    data[..4].copy_from_slice(&total.to_le_bytes());
+    data[4..HEADER_SIZE].copy_from_slice(&count.to_le_bytes());
+    data
+}
+
+// Integers come back as their decimal representation, the way Redis replies with them
+pub fn decode(data: &[u8]) -> Result<Vec<Bytes>, RdbError> {
+    let header: [u8; 4] = data
+        .get(..4)
+        .and_then(|x| x.try_into().ok())
+        .ok_or(RdbError::InvalidListpack)?;
+    if u32::from_le_bytes(header) as usize != data.len() || data.len() <= HEADER_SIZE {
+        return Err(RdbError::InvalidListpack);
+    }
+    let mut elements = Vec::new();
+    let mut index = HEADER_SIZE;
+    loop {
+        let first = *data.get(index).ok_or(RdbError::InvalidListpack)?;
+        if first == END {
+            break;
+        }
+        let start = index;
+        let element = match first {
+            x if x & 0x80 == ENCODING_7BIT_UINT => {
+                index += 1;
+                Bytes::from((x & 0x7f).to_string())
+            }
+            x if x & 0xc0 == ENCODING_6BIT_STR => {
+                let length = (x & 0x3f) as usize;
+                index += 1;
+                Bytes::copy_from_slice(read(data, &mut index, length)?)
+            }
+            x if x & 0xe0 == ENCODING_13BIT_INT => {
+                let low = read(data, &mut index, 2)?[1];
+                let value = (((x & 0x1f) as i64) << 8) | low as i64;
+                Bytes::from(sign_extend(value, 13).to_string())
+            }
+            x if x & 0xf0 == ENCODING_12BIT_STR => {
+                let low = read(data, &mut index, 2)?[1];
+                let length = (((x & 0x0f) as usize) << 8) | low as usize;
+                Bytes::copy_from_slice(read(data, &mut index, length)?)
+            }
+            ENCODING_32BIT_STR => {
+                let length = read_integer(data, &mut index, 4)? as u32 as usize;
+                Bytes::copy_from_slice(read(data, &mut index, length)?)
+            }
+            ENCODING_16BIT_INT => Bytes::from(read_integer(data, &mut index, 2)?.to_string()),
+            ENCODING_24BIT_INT => Bytes::from(read_integer(data, &mut index, 3)?.to_string()),
+            ENCODING_32BIT_INT => Bytes::from(read_integer(data, &mut index, 4)?.to_string()),
+            ENCODING_64BIT_INT => Bytes::from(read_integer(data, &mut index, 8)?.to_string()),
+            _ => return Err(RdbError::InvalidListpack),
+        };
+        // Every entry is followed by its own length, for walking the listpack backwards
+        let entry_length = index - start;
+        read(data, &mut index, backlen_size(entry_length))?;
+        elements.push(element);
+    }
+    if index != data.len() - 1 {
+        return Err(RdbError::InvalidListpack);
+    }
+    Ok(elements)
+}
+
+// Written so that it can be read from its last byte, seven bits at a time
+fn encode_backlen(length: usize, data: &mut Vec<u8>) {
+    let size = backlen_size(length);
+    for i in (0..size).rev() {
+        let bits = ((length >> (7 * i)) & 0x7f) as u8;
+        match i == size - 1 {
+            true => data.push(bits),
+            false => data.push(bits | 0x80),
+        }
+    }
+}
+
+fn backlen_size(length: usize) -> usize {
+    match length {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+fn read<'a>(data: &'a [u8], index: &mut usize, length: usize) -> Result<&'a [u8], RdbError> {
+    let slice = data
+        .get(*index..*index + length)
+        .ok_or(RdbError::InvalidListpack)?;
+    *index += length;
+    Ok(slice)
+}
+
+// Little endian integer of the given width following the encoding byte
+fn read_integer(data: &[u8], index: &mut usize, width: usize) -> Result<i64, RdbError> {
+    let bytes = &read(data, index, width + 1)?[1..];
+    let value = bytes
+        .iter()
+        .rev()
+        .fold(0i64, |value, byte| (value << 8) | *byte as i64);
+    Ok(sign_extend(value, width as u32 * 8))
+}
+
+fn sign_extend(value: i64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    (value << shift) >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A listpack holding the raw entries, each already starting with its encoding byte
+    fn pack(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE];
+        for entry in entries {
+            data.extend_from_slice(entry);
+            encode_backlen(entry.len(), &mut data);
+        }
+        data.push(END);
+        let total = data.len() as u32;
+        data[..4].copy_from_slice(&total.to_le_bytes());
+        data[4..HEADER_SIZE].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+        data
+    }
+
+    fn strings(elements: &[Bytes]) -> Vec<String> {
+        elements
+            .iter()
+            .map(|x| String::from_utf8_lossy(x).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_strings_of_every_length_encoding() {
+        let elements: Vec<Bytes> = [0, 1, 63, 64, 127, 4095, 4096, 20000]
+            .iter()
+            .map(|x| Bytes::from(vec![b'a'; *x]))
+            .chain([Bytes::from("007"), Bytes::from("-1"), Bytes::from("\0\r\n")])
+            .collect();
+        assert_eq!(decode(&encode(&elements)).unwrap(), elements);
+    }
+
+    #[test]
+    fn round_trips_more_entries_than_the_count_holds() {
+        let elements: Vec<Bytes> = (0..70000).map(|x| Bytes::from(x.to_string())).collect();
+        let data = encode(&elements);
+        assert_eq!(data[4..HEADER_SIZE], UNKNOWN_COUNT.to_le_bytes());
+        assert_eq!(decode(&data).unwrap(), elements);
+    }
+
+    #[test]
+    fn decodes_every_integer_encoding() {
+        let data = pack(&[
+            vec![ENCODING_7BIT_UINT | 5],
+            vec![ENCODING_7BIT_UINT | 127],
+            vec![ENCODING_13BIT_INT | 0x0f, 0xff],
+            vec![ENCODING_13BIT_INT | 0x10, 0x00],
+            vec![ENCODING_16BIT_INT, 0x30, 0x75],
+            vec![ENCODING_16BIT_INT, 0xff, 0xff],
+            vec![ENCODING_24BIT_INT, 0x00, 0x00, 0x80],
+            vec![ENCODING_32BIT_INT, 0xff, 0xff, 0xff, 0x7f],
+            vec![ENCODING_64BIT_INT, 0, 0, 0, 0, 0, 0, 0, 0x80],
+        ]);
+        assert_eq!(
+            strings(&decode(&data).unwrap()),
+            [
+                "5",
+                "127",
+                "4095",
+                "-4096",
+                "30000",
+                "-1",
+                "-8388608",
+                "2147483647",
+                "-9223372036854775808"
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_listpacks() {
+        let data = encode(&[Bytes::from("hello")]);
+        // Total length that doesn't match
+        let mut wrong_total = data.clone();
+        wrong_total[0] += 1;
+        assert!(decode(&wrong_total).is_err());
+        // Entry running past the end
+        let truncated = pack(&[vec![ENCODING_6BIT_STR | 10, b'a']]);
+        assert!(decode(&truncated).is_err());
+        // Missing terminator
+        let mut unterminated = data[..data.len() - 1].to_vec();
+        let total = unterminated.len() as u32;
+        unterminated[..4].copy_from_slice(&total.to_le_bytes());
+        assert!(decode(&unterminated).is_err());
+        assert!(decode(&[]).is_err());
+    }
+}
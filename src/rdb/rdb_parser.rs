@@ -0,0 +1,783 @@
+use super::*;
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub struct RdbParser {
+    data: Vec<u8>,
+    index: usize,
+    version: u32,
+}
+
+// A length-encoded field is either a plain length or a marker for a specially encoded string
+enum Length {
+    Plain(usize),
+    Special(u8),
+}
+
+impl RdbParser {
+    // Public
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            index: 0,
+            version: 0,
+        }
+    }
+
+    pub fn rdb_to_db(&mut self) -> Result<Dataset, RdbError> {
+        self.parse_header()?;
+        let mut database = Keyspace::new();
+        let mut expiry: HashMap<Bytes, SystemTime> = HashMap::new();
+        let mut pending_expiry: Option<SystemTime> = None;
+        // Reported in one line once the file is loaded
+        let mut aux_fields = vec![];
+        let mut empty_keys_skipped = 0;
+        loop {
+            let opcode = self.read_u8()?;
+            match opcode {
+                OPCODE_EOF => break,
+                OPCODE_AUX => {
+                    let key = self.parse_string()?;
+                    let value = self.parse_string()?;
+                    aux_fields.push(format!(
+                        "{}={}",
+                        String::from_utf8_lossy(&key),
+                        String::from_utf8_lossy(&value)
+                    ));
+                }
+                OPCODE_SELECT_DB => {
+                    // LumenDB has a single keyspace, so every database is loaded into it
+                    let db_number = self.parse_plain_length()?;
+                    println!("Loading RDB database {}", db_number);
+                }
+                OPCODE_RESIZE_DB => {
+                    let db_size = self.parse_plain_length()?;
+                    let expires_size = self.parse_plain_length()?;
+                    database.reserve(db_size);
+                    expiry.reserve(expires_size);
+                }
+                OPCODE_EXPIRY_MS => {
+                    let millis = u64::from_le_bytes(self.read_array::<8>()?);
+                    pending_expiry = Some(UNIX_EPOCH + Duration::from_millis(millis));
+                }
+                OPCODE_EXPIRY_S => {
+                    let secs = u32::from_le_bytes(self.read_array::<4>()?) as u64;
+                    pending_expiry = Some(UNIX_EPOCH + Duration::from_secs(secs));
+                }
+                OPCODE_IDLE => {
+                    // LRU idle time, we don't track eviction metadata
+                    self.parse_plain_length()?;
+                }
+                OPCODE_FREQ => {
+                    // LFU frequency, we don't track eviction metadata
+                    self.read_u8()?;
+                }
+                OPCODE_MODULE_AUX | OPCODE_FUNCTION => {
+                    return Err(RdbError::UnsupportedOpcode(opcode));
+                }
+                value_type => {
+                    let (key, value) = self.parse_key_value(value_type)?;
+                    // Older versions and other tools could save these, commands assume they
+                    // never exist
+                    if value.is_empty_collection() {
+                        pending_expiry = None;
+                        empty_keys_skipped += 1;
+                        continue;
+                    }
+                    match pending_expiry.take() {
+                        Some(x) => {
+                            expiry.insert(key.clone(), x);
+                        }
+                        None => {
+                            expiry.remove(&key);
+                        }
+                    }
+                    database.insert(key, value);
+                }
+            }
+        }
+        self.verify_checksum()?;
+        if !aux_fields.is_empty() {
+            println!("RDB aux fields: {}", aux_fields.join(" "));
+        }
+        if empty_keys_skipped > 0 {
+            println!("Skipped {} empty keys in the RDB file", empty_keys_skipped);
+        }
+        Ok((database, expiry))
+    }
+
+    // Data after the checksum is left untouched, e.g. the commands following an AOF preamble
+    pub fn bytes_parsed(&self) -> usize {
+        self.index
+    }
+
+    // Private
+    fn parse_header(&mut self) -> Result<(), RdbError> {
+        let magic = self.read_slice(MAGIC.len())?;
+        if magic != MAGIC {
+            return Err(RdbError::InvalidHeader);
+        }
+        let version = self.read_slice(4)?;
+        self.version = std::str::from_utf8(version)
+            .ok()
+            .and_then(|x| x.parse::<u32>().ok())
+            .ok_or(RdbError::InvalidHeader)?;
+        if self.version == 0 || self.version > MAX_SUPPORTED_VERSION {
+            return Err(RdbError::UnsupportedVersion(self.version));
+        }
+        println!("Loading RDB version {}", self.version);
+        Ok(())
+    }
+
+    fn parse_key_value(&mut self, value_type: u8) -> Result<(Bytes, Value), RdbError> {
+        let key = self.parse_string()?;
+        let value = match value_type {
+            TYPE_STRING => Value::String(self.parse_string()?),
+            TYPE_LIST => {
+                let length = self.parse_plain_length()?;
+                let mut list = QuickList::new();
+                for _ in 0..length {
+                    list.push_back(self.parse_string()?);
+                }
+                Value::List(list)
+            }
+            TYPE_LIST_ZIPLIST => Value::List(list_from(ziplist::decode(&self.parse_string()?)?)),
+            TYPE_LIST_QUICKLIST => {
+                let nodes = self.parse_plain_length()?;
+                let mut list = QuickList::new();
+                for _ in 0..nodes {
+                    for element in ziplist::decode(&self.parse_string()?)? {
+                        list.push_back(element);
+                    }
+                }
+                Value::List(list)
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.parse_plain_length()?;
+                let mut list = QuickList::new();
+                for _ in 0..nodes {
+                    let container = self.parse_plain_length()?;
+                    let node = self.parse_string()?;
+                    match container {
+                        QUICKLIST_NODE_PLAIN => list.push_back(node),
+                        QUICKLIST_NODE_PACKED => {
+                            for element in listpack::decode(&node)? {
+                                list.push_back(element);
+                            }
+                        }
+                        other => return Err(RdbError::UnsupportedQuicklistContainer(other)),
+                    }
+                }
+                Value::List(list)
+            }
+            TYPE_SET => {
+                let length = self.parse_plain_length()?;
+                let mut set = HashSet::new();
+                for _ in 0..length {
+                    set.insert(self.parse_string()?);
+                }
+                Value::Set(set)
+            }
+            TYPE_SET_INTSET => {
+                Value::Set(intset::decode(&self.parse_string()?)?.into_iter().collect())
+            }
+            TYPE_SET_LISTPACK => Value::Set(
+                listpack::decode(&self.parse_string()?)?
+                    .into_iter()
+                    .collect(),
+            ),
+            TYPE_HASH => {
+                let length = self.parse_plain_length()?;
+                let mut hash = HashMap::new();
+                for _ in 0..length {
+                    let field = self.parse_string()?;
+                    hash.insert(field, self.parse_string()?);
+                }
+                Value::Hash(hash)
+            }
+            TYPE_HASH_ZIPLIST => Value::Hash(hash_from(
+                ziplist::decode(&self.parse_string()?)?,
+                value_type,
+            )?),
+            TYPE_HASH_LISTPACK => Value::Hash(hash_from(
+                listpack::decode(&self.parse_string()?)?,
+                value_type,
+            )?),
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let length = self.parse_plain_length()?;
+                let mut sorted_set = SortedSet::new();
+                for _ in 0..length {
+                    let member = self.parse_string()?;
+                    let score = match value_type {
+                        TYPE_ZSET => self.parse_string_score()?,
+                        _ => f64::from_le_bytes(self.read_array::<8>()?),
+                    };
+                    sorted_set.insert(member, score);
+                }
+                Value::SortedSet(sorted_set)
+            }
+            TYPE_ZSET_ZIPLIST => Value::SortedSet(sorted_set_from(
+                ziplist::decode(&self.parse_string()?)?,
+                value_type,
+            )?),
+            TYPE_ZSET_LISTPACK => Value::SortedSet(sorted_set_from(
+                listpack::decode(&self.parse_string()?)?,
+                value_type,
+            )?),
+            other => {
+                return Err(RdbError::UnsupportedValueType(
+                    other,
+                    value_type_name(other),
+                ))
+            }
+        };
+        Ok((key, value))
+    }
+
+    // A score of the old sorted set type, written as a string prefixed by its length
+    fn parse_string_score(&mut self) -> Result<f64, RdbError> {
+        let score = match self.read_u8()? {
+            ZSET_SCORE_NAN => f64::NAN,
+            ZSET_SCORE_POS_INF => f64::INFINITY,
+            ZSET_SCORE_NEG_INF => f64::NEG_INFINITY,
+            length => {
+                let digits = self.read_slice(length as usize)?;
+                std::str::from_utf8(digits)
+                    .ok()
+                    .and_then(|x| x.parse().ok())
+                    .ok_or(RdbError::InvalidValue(TYPE_ZSET))?
+            }
+        };
+        Ok(score)
+    }
+
+    fn verify_checksum(&mut self) -> Result<(), RdbError> {
+        if self.version < MIN_CHECKSUM_VERSION {
+            return Ok(());
+        }
+        // The checksum covers everything up to and including the EOF opcode
+        let computed = crc64(0, &self.data[..self.index]);
+        let expected = u64::from_le_bytes(self.read_array::<8>()?);
+        // A zero checksum means the file was written with checksums disabled
+        if expected != 0 && expected != computed {
+            return Err(RdbError::ChecksumMismatch { expected, computed });
+        }
+        Ok(())
+    }
+
+    fn parse_length(&mut self) -> Result<Length, RdbError> {
+        let first = self.read_u8()?;
+        match first >> 6 {
+            LENGTH_6BIT => Ok(Length::Plain((first & 0x3f) as usize)),
+            LENGTH_14BIT => {
+                let second = self.read_u8()?;
+                Ok(Length::Plain(
+                    (((first & 0x3f) as usize) << 8) | second as usize,
+                ))
+            }
+            LENGTH_32OR64BIT => match first {
+                LENGTH_32BIT => Ok(Length::Plain(
+                    u32::from_be_bytes(self.read_array::<4>()?) as usize
+                )),
+                LENGTH_64BIT => Ok(Length::Plain(
+                    u64::from_be_bytes(self.read_array::<8>()?) as usize
+                )),
+                other => Err(RdbError::InvalidEncoding(other)),
+            },
+            LENGTH_SPECIAL => Ok(Length::Special(first & 0x3f)),
+            _ => unreachable!("A u8 shifted right by 6 only has two bits"),
+        }
+    }
+
+    fn parse_plain_length(&mut self) -> Result<usize, RdbError> {
+        match self.parse_length()? {
+            Length::Plain(x) => Ok(x),
+            Length::Special(x) => Err(RdbError::InvalidEncoding(x)),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<Bytes, RdbError> {
+        let bytes = match self.parse_length()? {
+            Length::Plain(length) => self.read_slice(length)?.to_vec(),
+            Length::Special(ENCODING_INT8) => (self.read_u8()? as i8).to_string().into_bytes(),
+            Length::Special(ENCODING_INT16) => i16::from_le_bytes(self.read_array::<2>()?)
+                .to_string()
+                .into_bytes(),
+            Length::Special(ENCODING_INT32) => i32::from_le_bytes(self.read_array::<4>()?)
+                .to_string()
+                .into_bytes(),
+            Length::Special(ENCODING_LZF) => {
+                let compressed_length = self.parse_plain_length()?;
+                let length = self.parse_plain_length()?;
+                let compressed = self.read_slice(compressed_length)?;
+                lzf_decompress(compressed, length)?
+            }
+            Length::Special(other) => return Err(RdbError::InvalidEncoding(other)),
+        };
+        Ok(Bytes::from(bytes))
+    }
+
+    fn read_u8(&mut self) -> Result<u8, RdbError> {
+        Ok(self.read_slice(1)?[0])
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], RdbError> {
+        let slice = self.read_slice(N)?;
+        Ok(slice
+            .try_into()
+            .expect("read_slice returns exactly N bytes"))
+    }
+
+    fn read_slice(&mut self, length: usize) -> Result<&[u8], RdbError> {
+        let end = self
+            .index
+            .checked_add(length)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(RdbError::UnexpectedEof(self.index))?;
+        let slice = &self.data[self.index..end];
+        self.index = end;
+        Ok(slice)
+    }
+}
+
+fn list_from(elements: Vec<Bytes>) -> QuickList {
+    let mut list = QuickList::new();
+    for element in elements {
+        list.push_back(element);
+    }
+    list
+}
+
+// Packed hashes alternate between fields and their values
+fn hash_from(elements: Vec<Bytes>, value_type: u8) -> Result<HashMap<Bytes, Bytes>, RdbError> {
+    if !elements.len().is_multiple_of(2) {
+        return Err(RdbError::InvalidValue(value_type));
+    }
+    let mut elements = elements.into_iter();
+    let mut hash = HashMap::new();
+    while let (Some(field), Some(value)) = (elements.next(), elements.next()) {
+        hash.insert(field, value);
+    }
+    Ok(hash)
+}
+
+// Packed sorted sets alternate between members and their scores, written as strings
+fn sorted_set_from(elements: Vec<Bytes>, value_type: u8) -> Result<SortedSet, RdbError> {
+    if !elements.len().is_multiple_of(2) {
+        return Err(RdbError::InvalidValue(value_type));
+    }
+    let mut elements = elements.into_iter();
+    let mut sorted_set = SortedSet::new();
+    while let (Some(member), Some(score)) = (elements.next(), elements.next()) {
+        let score = std::str::from_utf8(&score)
+            .ok()
+            .and_then(|x| x.parse::<f64>().ok())
+            .ok_or(RdbError::InvalidValue(value_type))?;
+        sorted_set.insert(member, score);
+    }
+    Ok(sorted_set)
+}
+
+// The most a 3 byte back reference can expand to, 264 bytes
+const LZF_MAX_RATIO: usize = 88;
+
+fn lzf_decompress(input: &[u8], expected_length: usize) -> Result<Vec<u8>, RdbError> {
+    // The length comes from the file, so never reserve more than the input could expand to
+    let capacity = expected_length.min(input.len().saturating_mul(LZF_MAX_RATIO));
+    let mut output: Vec<u8> = Vec::with_capacity(capacity);
+    let mut index = 0;
+    while index < input.len() {
+        let ctrl = input[index] as usize;
+        index += 1;
+        if ctrl < 32 {
+            // Literal run of ctrl + 1 bytes
+            let end = index + ctrl + 1;
+            if end > input.len() || output.len() + ctrl + 1 > expected_length {
+                return Err(RdbError::InvalidLzf);
+            }
+            output.extend_from_slice(&input[index..end]);
+            index = end;
+        } else {
+            // Back reference into the already decompressed output
+            let mut length = ctrl >> 5;
+            if length == 7 {
+                length += *input.get(index).ok_or(RdbError::InvalidLzf)? as usize;
+                index += 1;
+            }
+            let offset =
+                ((ctrl & 0x1f) << 8) + *input.get(index).ok_or(RdbError::InvalidLzf)? as usize + 1;
+            index += 1;
+            if offset > output.len() {
+                return Err(RdbError::InvalidLzf);
+            }
+            if output.len() + length + 2 > expected_length {
+                return Err(RdbError::InvalidLzf);
+            }
+            let start = output.len() - offset;
+            // References may overlap the bytes being written, so copy one at a time
+            for i in 0..length + 2 {
+                output.push(output[start + i]);
+            }
+        }
+    }
+    if output.len() != expected_length {
+        return Err(RdbError::InvalidLzf);
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdb::rdb_writer::RdbWriter;
+    use tokio::sync::mpsc;
+
+    // A list spread over several nodes, one of them holding a single element over the node size.
+    // Large enough to be streamed in more than one chunk
+    fn dataset() -> Dataset {
+        let mut list = QuickList::new();
+        for index in 0..300 {
+            list.push_back(Bytes::from(format!(
+                "element-{}-{}",
+                index,
+                "x".repeat(300)
+            )));
+        }
+        list.insert(150, Bytes::from(vec![b'a'; 10000]));
+        list.push_front(Bytes::from("12345"));
+        let mut database = Keyspace::new();
+        database.insert(Bytes::from("list"), Value::List(list));
+        database.insert(Bytes::from("string"), Value::String(Bytes::from("value")));
+        // Stored with millisecond precision
+        let expiration = UNIX_EPOCH
+            + Duration::from_millis(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis() as u64
+                    + 60_000,
+            );
+        let expiry = HashMap::from([(Bytes::from("list"), expiration)]);
+        (database, expiry)
+    }
+
+    fn list_elements(database: &Keyspace) -> Vec<Bytes> {
+        database
+            .get_list(&Bytes::from("list"))
+            .unwrap()
+            .expect("The list was loaded")
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    fn assert_same_dataset(loaded: &Dataset, original: &Dataset) {
+        assert_eq!(list_elements(&loaded.0), list_elements(&original.0));
+        assert_eq!(
+            loaded.0.get_string(&Bytes::from("string")).unwrap(),
+            Some(&Bytes::from("value"))
+        );
+        assert_eq!(loaded.1, original.1);
+    }
+
+    // A file of the given version holding the body, followed by EOF and the checksum
+    fn rdb_file(version: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(version);
+        data.extend_from_slice(body);
+        data.push(OPCODE_EOF);
+        let checksum = crc64(0, &data);
+        data.extend_from_slice(&checksum.to_le_bytes());
+        data
+    }
+
+    // A string value stored under a one letter key
+    fn string_entry(key: u8, encoded_value: &[u8]) -> Vec<u8> {
+        let mut entry = vec![TYPE_STRING, 1, key];
+        entry.extend_from_slice(encoded_value);
+        entry
+    }
+
+    fn load(data: Vec<u8>) -> Dataset {
+        RdbParser::new(data).rdb_to_db().unwrap()
+    }
+
+    fn string_of(database: &Keyspace, key: &str) -> Bytes {
+        database
+            .get_string(&Bytes::from(key.to_string()))
+            .unwrap()
+            .cloned()
+            .expect("The key was loaded")
+    }
+
+    // Two letter strings, each in its own ziplist entry
+    fn ziplist_of(elements: &[&[u8; 2]]) -> Vec<u8> {
+        let mut data = vec![0; 10];
+        let mut previous = 0;
+        for element in elements {
+            data.extend_from_slice(&[previous, 0x02]);
+            data.extend_from_slice(*element);
+            previous = 4;
+        }
+        data.push(0xff);
+        let total = data.len() as u32;
+        data[..4].copy_from_slice(&total.to_le_bytes());
+        data[8..10].copy_from_slice(&(elements.len() as u16).to_le_bytes());
+        data
+    }
+
+    fn with_length(data: &[u8]) -> Vec<u8> {
+        assert!(data.len() < 64);
+        let mut encoded = vec![data.len() as u8];
+        encoded.extend_from_slice(data);
+        encoded
+    }
+
+    #[test]
+    fn reads_every_length_encoding() {
+        let mut body = string_entry(b'a', b"\x05hello");
+        // 14 bits, big endian
+        body.extend(string_entry(b'b', &[0x41, 0x2c]));
+        body.extend(vec![b'b'; 300]);
+        // 32 and 64 bits, big endian
+        body.extend(string_entry(b'c', &[0x80, 0x00, 0x01, 0x11, 0x70]));
+        body.extend(vec![b'c'; 70000]);
+        body.extend(string_entry(b'd', &[0x81, 0, 0, 0, 0, 0, 0, 0, 0x02]));
+        body.extend(b"dd");
+        let (database, _) = load(rdb_file(b"0011", &body));
+        assert_eq!(string_of(&database, "a"), "hello");
+        assert_eq!(string_of(&database, "b"), vec![b'b'; 300]);
+        assert_eq!(string_of(&database, "c"), vec![b'c'; 70000]);
+        assert_eq!(string_of(&database, "d"), "dd");
+    }
+
+    #[test]
+    fn reads_integer_encoded_strings() {
+        let mut body = string_entry(b'a', &[0xc0, 0xfb]);
+        body.extend(string_entry(b'b', &[0xc1, 0xe8, 0x03]));
+        body.extend(string_entry(b'c', &[0xc2, 0x60, 0x79, 0xfe, 0xff]));
+        let (database, _) = load(rdb_file(b"0011", &body));
+        assert_eq!(string_of(&database, "a"), "-5");
+        assert_eq!(string_of(&database, "b"), "1000");
+        assert_eq!(string_of(&database, "c"), "-100000");
+    }
+
+    #[test]
+    fn reads_lzf_compressed_strings() {
+        // A literal "abc" followed by a back reference copying 9 bytes from 3 bytes back
+        let compressed = [0x02, b'a', b'b', b'c', 0xe0, 0x00, 0x02];
+        let mut value = vec![0xc3, compressed.len() as u8, 12];
+        value.extend_from_slice(&compressed);
+        let (database, _) = load(rdb_file(b"0011", &string_entry(b'a', &value)));
+        assert_eq!(string_of(&database, "a"), "abcabcabcabc");
+    }
+
+    #[test]
+    fn rejects_corrupt_lzf() {
+        // Reference reaching back before the start of the output
+        assert!(lzf_decompress(&[0x20, 0x05], 3).is_err());
+        // Output longer or shorter than announced
+        assert!(lzf_decompress(&[0x02, b'a', b'b', b'c'], 2).is_err());
+        assert!(lzf_decompress(&[0x02, b'a', b'b', b'c'], 4).is_err());
+        assert!(lzf_decompress(&[0x02, b'a', b'b', b'c', 0xe0, 0x00, 0x02], 11).is_err());
+        // Literal run past the end of the input
+        assert!(lzf_decompress(&[0x05, b'a'], 6).is_err());
+    }
+
+    #[test]
+    fn lzf_does_not_trust_the_announced_length() {
+        // Would reserve a terabyte if the length from the file were taken at face value
+        assert!(lzf_decompress(&[0x00, b'a'], 1 << 40).is_err());
+        let mut value = vec![0xc3, 0x02, 0x81, 0, 0, 1, 0, 0, 0, 0, 0];
+        value.extend_from_slice(&[0x00, b'a']);
+        let result = RdbParser::new(rdb_file(b"0011", &string_entry(b'a', &value))).rdb_to_db();
+        assert!(matches!(result, Err(RdbError::InvalidLzf)));
+    }
+
+    #[test]
+    fn verifies_the_checksum() {
+        let mut data = rdb_file(b"0011", &string_entry(b'a', b"\x01x"));
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        assert!(matches!(
+            RdbParser::new(data.clone()).rdb_to_db(),
+            Err(RdbError::ChecksumMismatch { .. })
+        ));
+        // Written with checksums turned off
+        data[last - 7..].fill(0);
+        assert!(RdbParser::new(data).rdb_to_db().is_ok());
+        // No checksum before version 5
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(b"0004");
+        data.extend(string_entry(b'a', b"\x01x"));
+        data.push(OPCODE_EOF);
+        assert!(RdbParser::new(data).rdb_to_db().is_ok());
+    }
+
+    #[test]
+    fn rejects_truncated_and_corrupt_files() {
+        let mut body = vec![OPCODE_AUX];
+        body.extend(with_length(b"redis-ver"));
+        body.extend(with_length(b"7.2.0"));
+        body.extend([OPCODE_SELECT_DB, 0, OPCODE_RESIZE_DB, 1, 1]);
+        body.push(OPCODE_EXPIRY_MS);
+        body.extend(u64::MAX.to_le_bytes());
+        body.extend(string_entry(b'a', b"\x05hello"));
+        let data = rdb_file(b"0011", &body);
+        assert!(RdbParser::new(data.clone()).rdb_to_db().is_ok());
+        for length in 0..data.len() {
+            assert!(RdbParser::new(data[..length].to_vec()).rdb_to_db().is_err());
+        }
+        let mut wrong_magic = data.clone();
+        wrong_magic[0] = b'X';
+        assert!(matches!(
+            RdbParser::new(wrong_magic).rdb_to_db(),
+            Err(RdbError::InvalidHeader)
+        ));
+        assert!(matches!(
+            RdbParser::new(rdb_file(b"0012", &[])).rdb_to_db(),
+            Err(RdbError::UnsupportedVersion(12))
+        ));
+        let mut stream = vec![15, 1, b's'];
+        stream.extend([0; 8]);
+        assert!(matches!(
+            RdbParser::new(rdb_file(b"0011", &stream)).rdb_to_db(),
+            Err(RdbError::UnsupportedValueType(15, "stream"))
+        ));
+    }
+
+    #[test]
+    fn reads_the_packed_encodings_of_older_versions() {
+        let mut body = vec![OPCODE_SELECT_DB, 0];
+        // Quicklist of two ziplists
+        body.extend([TYPE_LIST_QUICKLIST, 1, b'l', 2]);
+        body.extend(with_length(&ziplist_of(&[b"l1", b"l2"])));
+        body.extend(with_length(&ziplist_of(&[b"l3"])));
+        body.extend([TYPE_LIST_ZIPLIST, 1, b'z']);
+        body.extend(with_length(&ziplist_of(&[b"z1", b"z2"])));
+        body.extend([TYPE_HASH_ZIPLIST, 1, b'h']);
+        body.extend(with_length(&ziplist_of(&[b"f1", b"v1", b"f2", b"v2"])));
+        body.extend([TYPE_ZSET_ZIPLIST, 1, b's']);
+        body.extend(with_length(&ziplist_of(&[b"m1", b"10", b"m2", b"-5"])));
+        // Intset of two 16 bit members
+        body.extend([
+            TYPE_SET_INTSET,
+            1,
+            b'i',
+            12,
+            2,
+            0,
+            0,
+            0,
+            2,
+            0,
+            0,
+            0,
+            0xff,
+            0xff,
+            7,
+            0,
+        ]);
+        // Old sorted set, scores written as strings
+        body.extend([TYPE_ZSET, 1, b'o', 2, 1, b'a', 3, b'1', b'.', b'5', 1, b'b']);
+        body.push(ZSET_SCORE_POS_INF);
+        let (database, _) = load(rdb_file(b"0009", &body));
+
+        let list: Vec<Bytes> = database
+            .get_list(&Bytes::from("l"))
+            .unwrap()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect();
+        assert_eq!(list, ["l1", "l2", "l3"]);
+        assert_eq!(
+            database.get_list(&Bytes::from("z")).unwrap().unwrap().len(),
+            2
+        );
+        match database.get(&Bytes::from("h")) {
+            Some(Value::Hash(x)) => {
+                assert_eq!(x.len(), 2);
+                assert_eq!(x.get(&Bytes::from("f2")), Some(&Bytes::from("v2")));
+            }
+            other => panic!("Expected a hash, got {:?}", other),
+        }
+        match database.get(&Bytes::from("s")) {
+            Some(Value::SortedSet(x)) => {
+                assert_eq!(x.score(&Bytes::from("m1")), Some(10.0));
+                assert_eq!(x.score(&Bytes::from("m2")), Some(-5.0));
+            }
+            other => panic!("Expected a sorted set, got {:?}", other),
+        }
+        match database.get(&Bytes::from("i")) {
+            Some(Value::Set(x)) => {
+                assert!(x.contains(&Bytes::from("-1")) && x.contains(&Bytes::from("7")));
+            }
+            other => panic!("Expected a set, got {:?}", other),
+        }
+        match database.get(&Bytes::from("o")) {
+            Some(Value::SortedSet(x)) => {
+                assert_eq!(x.score(&Bytes::from("a")), Some(1.5));
+                assert_eq!(x.score(&Bytes::from("b")), Some(f64::INFINITY));
+            }
+            other => panic!("Expected a sorted set, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn round_trips_a_list() {
+        let original = dataset();
+        let rdb = RdbWriter::new().db_to_rdb(&original.0, &original.1);
+        let loaded = RdbParser::new(rdb).rdb_to_db().unwrap();
+        assert_same_dataset(&loaded, &original);
+    }
+
+    #[tokio::test]
+    async fn round_trips_a_list_streamed_in_chunks() {
+        let original = dataset();
+        let (sink, mut chunks) = mpsc::channel(1);
+        let serialize = async {
+            let sent = RdbWriter::new()
+                .db_to_sink(&original.0, &original.1, &sink)
+                .await;
+            drop(sink);
+            sent
+        };
+        let collect = async {
+            let mut rdb = vec![];
+            while let Some(chunk) = chunks.recv().await {
+                rdb.extend_from_slice(&chunk);
+            }
+            rdb
+        };
+        let (sent, rdb) = tokio::join!(serialize, collect);
+        assert_eq!(sent.unwrap(), rdb.len());
+        assert!(rdb.len() > 64 * 1024);
+        let loaded = RdbParser::new(rdb).rdb_to_db().unwrap();
+        assert_same_dataset(&loaded, &original);
+    }
+
+    #[test]
+    fn rejects_a_corrupted_list() {
+        let original = dataset();
+        let mut rdb = RdbWriter::new().db_to_rdb(&original.0, &original.1);
+        let index = rdb.windows(11).position(|x| x == b"element-150").unwrap();
+        rdb[index] = b'E';
+        assert!(RdbParser::new(rdb).rdb_to_db().is_err());
+    }
+
+    #[test]
+    fn skips_empty_keys() {
+        // An empty list and an empty set with an expiry, then a string
+        let mut body = vec![TYPE_LIST, 1, b'l', 0];
+        body.push(OPCODE_EXPIRY_MS);
+        body.extend_from_slice(&u64::MAX.to_le_bytes());
+        body.extend_from_slice(&[TYPE_SET, 1, b's', 0]);
+        body.extend_from_slice(&string_entry(b'a', &[1, b'x']));
+        let (database, expiry) = load(rdb_file(b"0011", &body));
+        assert_eq!(database.len(), 1);
+        assert_eq!(string_of(&database, "a"), Bytes::from("x"));
+        assert!(expiry.is_empty());
+    }
+}
@@ -0,0 +1,234 @@
+use super::*;
+
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+const WRITER_VERSION: &[u8] = b"0011";
+pub const REDIS_VERSION: &str = "7.2.0";
+
+// Chunks handed to an async sink once this much has been serialized
+const SINK_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Default)]
+pub struct RdbWriter {
+    data: Vec<u8>,
+    // Checksum of everything already handed to a sink
+    checksum: u64,
+}
+
+impl RdbWriter {
+    // Public
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn db_to_rdb(
+        mut self,
+        database: &Keyspace,
+        expiry: &HashMap<Bytes, SystemTime>,
+    ) -> Vec<u8> {
+        let live_keys = self.write_preamble(database, expiry);
+        for (key, value, expiration) in live_keys {
+            self.write_entry(key, value, expiration);
+        }
+        self.write_trailer();
+        self.data
+    }
+
+    // Like db_to_rdb, but sends the file to the sink in chunks as it is serialized rather than
+    // building all of it in memory. Returns the size of the file, or an error once the sink is
+    // closed
+    pub async fn db_to_sink(
+        mut self,
+        database: &Keyspace,
+        expiry: &HashMap<Bytes, SystemTime>,
+        sink: &mpsc::Sender<Bytes>,
+    ) -> Result<usize, mpsc::error::SendError<Bytes>> {
+        let mut sent = 0;
+        let live_keys = self.write_preamble(database, expiry);
+        for (key, value, expiration) in live_keys {
+            self.write_entry(key, value, expiration);
+            if self.data.len() >= SINK_CHUNK_SIZE {
+                sent += self.flush_to(sink).await?;
+            }
+        }
+        self.write_trailer();
+        sent += self.flush_to(sink).await?;
+        Ok(sent)
+    }
+
+    // Private
+    // Writes everything up to the first key, returning the keys that haven't expired yet
+    fn write_preamble<'a>(
+        &mut self,
+        database: &'a Keyspace,
+        expiry: &'a HashMap<Bytes, SystemTime>,
+    ) -> Vec<(&'a Bytes, &'a Value, Option<&'a SystemTime>)> {
+        let now = SystemTime::now();
+        // Keys that have already expired are dropped rather than persisted
+        let live_keys: Vec<(&Bytes, &Value, Option<&SystemTime>)> = database
+            .iter()
+            .map(|(key, value)| (key, value, expiry.get(key)))
+            .filter(|(_, _, expiration)| match expiration {
+                Some(x) => **x > now,
+                None => true,
+            })
+            .collect();
+        let expires_count = live_keys.iter().filter(|(_, _, x)| x.is_some()).count();
+
+        self.write_header();
+        self.write_aux_fields(now);
+        self.data.push(OPCODE_SELECT_DB);
+        self.write_length(0);
+        self.data.push(OPCODE_RESIZE_DB);
+        self.write_length(live_keys.len());
+        self.write_length(expires_count);
+        live_keys
+    }
+
+    fn write_entry(&mut self, key: &[u8], value: &Value, expiration: Option<&SystemTime>) {
+        if let Some(expiration) = expiration {
+            self.write_expiry(expiration);
+        }
+        self.write_key_value(key, value);
+    }
+
+    fn write_trailer(&mut self) {
+        self.data.push(OPCODE_EOF);
+        let checksum = crc64(self.checksum, &self.data);
+        self.data.extend_from_slice(&checksum.to_le_bytes());
+    }
+
+    async fn flush_to(
+        &mut self,
+        sink: &mpsc::Sender<Bytes>,
+    ) -> Result<usize, mpsc::error::SendError<Bytes>> {
+        self.checksum = crc64(self.checksum, &self.data);
+        let chunk = Bytes::from(std::mem::take(&mut self.data));
+        let length = chunk.len();
+        sink.send(chunk).await?;
+        Ok(length)
+    }
+
+    fn write_header(&mut self) {
+        self.data.extend_from_slice(MAGIC);
+        self.data.extend_from_slice(WRITER_VERSION);
+    }
+
+    fn write_aux_fields(&mut self, now: SystemTime) {
+        let ctime = now
+            .duration_since(UNIX_EPOCH)
+            .expect("System clock is before the unix epoch")
+            .as_secs();
+        let bits = (usize::BITS as u64).to_string();
+        for (key, value) in [
+            ("redis-ver", REDIS_VERSION.to_string()),
+            ("redis-bits", bits),
+            ("ctime", ctime.to_string()),
+        ] {
+            self.data.push(OPCODE_AUX);
+            self.write_string(key.as_bytes());
+            self.write_string(value.as_bytes());
+        }
+    }
+
+    fn write_key_value(&mut self, key: &[u8], value: &Value) {
+        match value {
+            Value::String(x) => {
+                self.data.push(TYPE_STRING);
+                self.write_string(key);
+                self.write_string(x);
+            }
+            // Each quicklist node is stored as one listpack, like Redis does
+            Value::List(x) => {
+                self.data.push(TYPE_LIST_QUICKLIST_2);
+                self.write_string(key);
+                self.write_length(x.nodes().count());
+                for node in x.nodes() {
+                    self.write_length(QUICKLIST_NODE_PACKED);
+                    self.write_string(&listpack::encode(node));
+                }
+            }
+            Value::Set(x) => {
+                self.data.push(TYPE_SET);
+                self.write_string(key);
+                self.write_length(x.len());
+                for member in x {
+                    self.write_string(member);
+                }
+            }
+            Value::Hash(x) => {
+                self.data.push(TYPE_HASH);
+                self.write_string(key);
+                self.write_length(x.len());
+                for (field, value) in x {
+                    self.write_string(field);
+                    self.write_string(value);
+                }
+            }
+            Value::SortedSet(x) => {
+                self.data.push(TYPE_ZSET_2);
+                self.write_string(key);
+                self.write_length(x.len());
+                for (member, score) in x.iter() {
+                    self.write_string(member);
+                    self.data.extend_from_slice(&score.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    fn write_expiry(&mut self, expiration: &SystemTime) {
+        let millis = expiration
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_millis() as u64)
+            .unwrap_or(0);
+        self.data.push(OPCODE_EXPIRY_MS);
+        self.data.extend_from_slice(&millis.to_le_bytes());
+    }
+
+    fn write_length(&mut self, length: usize) {
+        if length < 1 << 6 {
+            self.data.push((LENGTH_6BIT << 6) | length as u8);
+        } else if length < 1 << 14 {
+            self.data.push((LENGTH_14BIT << 6) | (length >> 8) as u8);
+            self.data.push(length as u8);
+        } else if length <= u32::MAX as usize {
+            self.data.push(LENGTH_32BIT);
+            self.data.extend_from_slice(&(length as u32).to_be_bytes());
+        } else {
+            self.data.push(LENGTH_64BIT);
+            self.data.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+
+    fn write_string(&mut self, value: &[u8]) {
+        // Strings that round trip through an integer are stored in their integer encoding
+        if let Some(number) = std::str::from_utf8(value)
+            .ok()
+            .and_then(|x| x.parse::<i32>().ok())
+        {
+            if number.to_string().as_bytes() == value {
+                self.write_integer(number);
+                return;
+            }
+        }
+        self.write_length(value.len());
+        self.data.extend_from_slice(value);
+    }
+
+    fn write_integer(&mut self, number: i32) {
+        let special = LENGTH_SPECIAL << 6;
+        if let Ok(x) = i8::try_from(number) {
+            self.data.push(special | ENCODING_INT8);
+            self.data.extend_from_slice(&x.to_le_bytes());
+        } else if let Ok(x) = i16::try_from(number) {
+            self.data.push(special | ENCODING_INT16);
+            self.data.extend_from_slice(&x.to_le_bytes());
+        } else {
+            self.data.push(special | ENCODING_INT32);
+            self.data.extend_from_slice(&number.to_le_bytes());
+        }
+    }
+}
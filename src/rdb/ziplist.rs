@@ -0,0 +1,212 @@
+use super::RdbError;
+
+use bytes::Bytes;
+
+// Total byte count, offset of the last entry and number of entries, all little endian
+const HEADER_SIZE: usize = 10;
+const END: u8 = 0xff;
+// A previous entry length of 254 or more is stored in the four bytes after this marker
+const PREVLEN_LONG: u8 = 0xfe;
+
+// Entry encodings, string ones identified by the two high bits of the first byte
+const ENCODING_6BIT_STR: u8 = 0x00;
+const ENCODING_14BIT_STR: u8 = 0x40;
+const ENCODING_32BIT_STR: u8 = 0x80;
+const ENCODING_16BIT_INT: u8 = 0xc0;
+const ENCODING_32BIT_INT: u8 = 0xd0;
+const ENCODING_64BIT_INT: u8 = 0xe0;
+const ENCODING_24BIT_INT: u8 = 0xf0;
+const ENCODING_8BIT_INT: u8 = 0xfe;
+// 0xf1 to 0xfd hold the values 0 to 12 in the low four bits, offset by one
+const ENCODING_IMMEDIATE_MIN: u8 = 0xf1;
+const ENCODING_IMMEDIATE_MAX: u8 = 0xfd;
+
+// The format lists, hashes and sorted sets were packed in before listpacks replaced it in
+// Redis 7. Integers come back as their decimal representation, like listpack::decode
+pub fn decode(data: &[u8]) -> Result<Vec<Bytes>, RdbError> {
+    let header: [u8; 4] = data
+        .get(..4)
+        .and_then(|x| x.try_into().ok())
+        .ok_or(RdbError::InvalidZiplist)?;
+    if u32::from_le_bytes(header) as usize != data.len() || data.len() <= HEADER_SIZE {
+        return Err(RdbError::InvalidZiplist);
+    }
+    let mut elements = Vec::new();
+    let mut index = HEADER_SIZE;
+    loop {
+        let first = *data.get(index).ok_or(RdbError::InvalidZiplist)?;
+        if first == END {
+            break;
+        }
+        // Every entry starts with the length of the one before it, for walking backwards
+        match first {
+            PREVLEN_LONG => read(data, &mut index, 5)?,
+            _ => read(data, &mut index, 1)?,
+        };
+        let encoding = *data.get(index).ok_or(RdbError::InvalidZiplist)?;
+        let element = match encoding {
+            x if x & 0xc0 == ENCODING_6BIT_STR => {
+                index += 1;
+                let length = (x & 0x3f) as usize;
+                Bytes::copy_from_slice(read(data, &mut index, length)?)
+            }
+            x if x & 0xc0 == ENCODING_14BIT_STR => {
+                let low = read(data, &mut index, 2)?[1];
+                let length = (((x & 0x3f) as usize) << 8) | low as usize;
+                Bytes::copy_from_slice(read(data, &mut index, length)?)
+            }
+            ENCODING_32BIT_STR => {
+                let bytes: [u8; 4] = read(data, &mut index, 5)?[1..]
+                    .try_into()
+                    .expect("Read exactly 4 bytes");
+                let length = u32::from_be_bytes(bytes) as usize;
+                Bytes::copy_from_slice(read(data, &mut index, length)?)
+            }
+            ENCODING_8BIT_INT => Bytes::from(read_integer(data, &mut index, 1)?.to_string()),
+            ENCODING_16BIT_INT => Bytes::from(read_integer(data, &mut index, 2)?.to_string()),
+            ENCODING_24BIT_INT => Bytes::from(read_integer(data, &mut index, 3)?.to_string()),
+            ENCODING_32BIT_INT => Bytes::from(read_integer(data, &mut index, 4)?.to_string()),
+            ENCODING_64BIT_INT => Bytes::from(read_integer(data, &mut index, 8)?.to_string()),
+            x @ ENCODING_IMMEDIATE_MIN..=ENCODING_IMMEDIATE_MAX => {
+                index += 1;
+                Bytes::from(((x & 0x0f) - 1).to_string())
+            }
+            _ => return Err(RdbError::InvalidZiplist),
+        };
+        elements.push(element);
+    }
+    if index != data.len() - 1 {
+        return Err(RdbError::InvalidZiplist);
+    }
+    Ok(elements)
+}
+
+fn read<'a>(data: &'a [u8], index: &mut usize, length: usize) -> Result<&'a [u8], RdbError> {
+    let slice = data
+        .get(*index..*index + length)
+        .ok_or(RdbError::InvalidZiplist)?;
+    *index += length;
+    Ok(slice)
+}
+
+// Little endian integer of the given width following the encoding byte
+fn read_integer(data: &[u8], index: &mut usize, width: usize) -> Result<i64, RdbError> {
+    let bytes = &read(data, index, width + 1)?[1..];
+    let value = bytes
+        .iter()
+        .rev()
+        .fold(0i64, |value, byte| (value << 8) | *byte as i64);
+    let shift = 64 - width as u32 * 8;
+    Ok((value << shift) >> shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A ziplist holding the raw entries, each already starting with its encoding byte
+    fn pack(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE];
+        let mut previous = 0;
+        let mut tail = HEADER_SIZE;
+        for entry in entries {
+            tail = data.len();
+            let start = data.len();
+            match previous < PREVLEN_LONG as usize {
+                true => data.push(previous as u8),
+                false => {
+                    data.push(PREVLEN_LONG);
+                    data.extend_from_slice(&(previous as u32).to_le_bytes());
+                }
+            }
+            data.extend_from_slice(entry);
+            previous = data.len() - start;
+        }
+        data.push(END);
+        let total = data.len() as u32;
+        data[..4].copy_from_slice(&total.to_le_bytes());
+        data[4..8].copy_from_slice(&(tail as u32).to_le_bytes());
+        data[8..HEADER_SIZE].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+        data
+    }
+
+    fn strings(elements: &[Bytes]) -> Vec<String> {
+        elements
+            .iter()
+            .map(|x| String::from_utf8_lossy(x).to_string())
+            .collect()
+    }
+
+    fn string_entry(value: &[u8]) -> Vec<u8> {
+        let mut entry = match value.len() {
+            x if x < 1 << 6 => vec![ENCODING_6BIT_STR | x as u8],
+            x if x < 1 << 14 => vec![ENCODING_14BIT_STR | (x >> 8) as u8, x as u8],
+            x => {
+                let mut header = vec![ENCODING_32BIT_STR];
+                header.extend_from_slice(&(x as u32).to_be_bytes());
+                header
+            }
+        };
+        entry.extend_from_slice(value);
+        entry
+    }
+
+    #[test]
+    fn decodes_strings_of_every_length_encoding() {
+        let values: Vec<Vec<u8>> = [0, 1, 63, 64, 300, 16383, 16384]
+            .iter()
+            .map(|x| vec![b'a'; *x])
+            .collect();
+        let entries: Vec<Vec<u8>> = values.iter().map(|x| string_entry(x)).collect();
+        let decoded = decode(&pack(&entries)).unwrap();
+        assert_eq!(decoded, values);
+    }
+
+    #[test]
+    fn decodes_every_integer_encoding() {
+        let data = pack(&[
+            vec![ENCODING_IMMEDIATE_MIN],
+            vec![ENCODING_IMMEDIATE_MAX],
+            vec![ENCODING_8BIT_INT, 0x80],
+            vec![ENCODING_16BIT_INT, 0x30, 0x75],
+            vec![ENCODING_24BIT_INT, 0xff, 0xff, 0xff],
+            vec![ENCODING_32BIT_INT, 0x00, 0x00, 0x00, 0x80],
+            vec![
+                ENCODING_64BIT_INT,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0x7f,
+            ],
+        ]);
+        assert_eq!(
+            strings(&decode(&data).unwrap()),
+            [
+                "0",
+                "12",
+                "-128",
+                "30000",
+                "-1",
+                "-2147483648",
+                "9223372036854775807"
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_ziplists() {
+        let data = pack(&[string_entry(b"hello")]);
+        let mut wrong_total = data.clone();
+        wrong_total[0] += 1;
+        assert!(decode(&wrong_total).is_err());
+        // String running past the end
+        assert!(decode(&pack(&[vec![ENCODING_6BIT_STR | 10, b'a']])).is_err());
+        // Unused encoding
+        assert!(decode(&pack(&[vec![0xf0 | 0x0e]])).is_err());
+        assert!(decode(&[]).is_err());
+    }
+}
@@ -1,25 +1,44 @@
-use self::commands::Command;
+use self::aof::Aof;
+use self::commands::{Command, CommandError};
+use self::connection::{split_connection, ConnectionWriter};
+use self::keyspace::Keyspace;
+use self::persistence::SaveState;
 use self::processing::*;
-use self::replica::is_stream_replica;
+use self::replication::{ReplicaLink, ReplicationState};
 use self::synchronize::construct_rdb;
 
 use crate::config::Config;
-use crate::rdb::RdbParser;
+use crate::rdb::rdb_parser::RdbParser;
 use crate::resp::resp_deserializer::RespParser;
+use crate::resp::resp_serializer::{serialize_command, serialize_resp_data};
+use crate::resp::{Protocol, RespType};
 
+use bytes::Bytes;
 use core::fmt;
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Mutex, RwLock};
-use tokio::task;
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, Notify, RwLock};
+use tokio::task::{self, JoinHandle};
+use tokio::time::Duration;
 
+pub mod aof;
 pub mod commands;
+pub mod connection;
+pub mod expire;
+pub mod failover;
+pub mod keyspace;
+pub mod lists;
+pub mod persistence;
 pub mod processing;
+pub mod quicklist;
 pub mod replica;
+pub mod replication;
 pub mod synchronize;
 
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -37,226 +56,610 @@ impl fmt::Display for RedisState {
     }
 }
 
+// Connected replicas keyed by their connection id. Replicas keep one as well, for the
+// sub-replicas they forward their master's stream to
+pub type ReplicaConnections = Arc<RwLock<HashMap<u64, ReplicaLink>>>;
+
+#[derive(Clone)]
 pub struct Redis {
-    database: Arc<Mutex<HashMap<String, String>>>,
-    expiry: Arc<RwLock<HashMap<String, SystemTime>>>,
+    database: Arc<Mutex<Keyspace>>,
+    expiry: Arc<RwLock<HashMap<Bytes, SystemTime>>>,
     config: Arc<Config>,
-    listener: TcpListener,
-    replica_connections: Arc<RwLock<Option<HashMap<i32, Arc<RwLock<TcpStream>>>>>>,
-    master_connection: Option<Arc<RwLock<TcpStream>>>,
+    listener: Arc<TcpListener>,
+    replica_connections: ReplicaConnections,
+    save_state: Arc<Mutex<SaveState>>,
+    // Held while a write command is propagated and applied, so a snapshot taken under it lines
+    // up exactly with the replication stream
+    write_lock: Arc<Mutex<()>>,
+    aof: Option<Arc<Aof>>,
+    replication: Arc<Mutex<ReplicationState>>,
+    // Task keeping a replica connected to its master, see replica::maintain_master_link
+    master_link: Arc<Mutex<Option<JoinHandle<()>>>>,
+    // Notified whenever a replica acknowledges an offset, woken WAITs recount their replicas
+    replica_acks: Arc<Notify>,
+    // Replicas waiting for the next diskless transfer, keyed like replica_connections
+    diskless_syncs: Arc<Mutex<Vec<(u64, ReplicaLink)>>>,
 }
 
 impl Redis {
-    async fn handle_conn(&mut self, stream: Arc<RwLock<TcpStream>>, parser: Option<RespParser>) {
-        let database: Arc<Mutex<HashMap<String, String>>> = Arc::clone(&self.database);
+    fn handle_conn(
+        &self,
+        mut parser: RespParser,
+        writer: ConnectionWriter,
+        is_master_link: bool,
+    ) -> JoinHandle<()> {
+        let database: Arc<Mutex<Keyspace>> = Arc::clone(&self.database);
         let config = Arc::clone(&self.config);
         let replica_connections = Arc::clone(&self.replica_connections);
         let expiry = Arc::clone(&self.expiry);
-        // Each connection should have a dedicated parser
-        let mut parser = match parser {
-            Some(x) => x,
-            None => RespParser::new(String::from(""), Arc::clone(&stream)),
-        };
-        let mut total_bytes_processed = 0;
-        let mut write_bytes_processed = 0;
+        let save_state = Arc::clone(&self.save_state);
+        let write_lock = Arc::clone(&self.write_lock);
+        let aof = self.aof.clone();
+        let replication = Arc::clone(&self.replication);
+        let replica_acks = Arc::clone(&self.replica_acks);
+        let redis = self.clone();
+        // Replication offset right after this client's most recent write
+        let mut last_write_offset = 0;
         let mut write_commands_to_process = 0;
+        // Announced by replicas during the handshake, reported by ROLE
+        let mut listening_port = String::new();
+        // Set once this connection turns out to be a replica, which keeps sending ACKs on it
+        let mut replica_id: Option<u64> = None;
+        // Whether the replica can read an RDB framed by an EOF marker
+        let mut capa_eof = false;
+        // Every connection starts on RESP2 until it switches with HELLO
+        let mut protocol = Protocol::Resp2;
         task::spawn(async move {
+            // Replies waiting to be written, a pipeline's replies go out together once every
+            // command read so far has been run
+            let mut output = Vec::new();
             loop {
-                // If a stream is a replica stream, don't automatically listen to it after the
-                // handshake
-                let command: Command;
-                if !is_stream_replica(Arc::clone(&replica_connections), Arc::clone(&stream)).await {
-                    if let Some((comm, bytes)) = parser.parse_command().await {
-                        // Increase bytes processed every time we process a command
-                        command = comm;
-                        if config.role == RedisState::Replica {
-                            total_bytes_processed += bytes;
-                        } else if command.is_write() {
-                            write_bytes_processed += bytes;
-                            write_commands_to_process += 1;
+                let parsed = match parser.parse_buffered_command() {
+                    Some(x) => Some(x),
+                    None => {
+                        // Stop reading from a client that got dropped for not reading its replies
+                        if !flush_output(&writer, &mut output) {
+                            break;
+                        }
+                        parser.parse_command().await
+                    }
+                };
+                let (command, raw_command) = match parsed {
+                    Some((Ok(command), raw_command)) => (command, raw_command),
+                    Some((Err(error), raw_command)) => {
+                        let is_protocol_error = matches!(error, CommandError::Protocol(_));
+                        handle_command_error(error, &mut output, is_master_link).await;
+                        // Nothing after a request that couldn't be framed can be trusted
+                        if is_protocol_error {
+                            break;
+                        }
+                        // The master ran it, e.g. a command this version doesn't know, so it
+                        // still counts towards the offset
+                        if is_master_link {
+                            let _write_guard = write_lock.lock().await;
+                            synchronize::account_master_stream(
+                                &raw_command,
+                                Arc::clone(&replica_connections),
+                                Arc::clone(&replication),
+                            )
+                            .await;
                         }
-                    } else {
-                        // other side has ended connection
-                        break;
+                        continue;
                     }
+                    // other side has ended connection
+                    None => break,
+                };
+
+                // A master deletes expired keys, propagating the DEL, before a list command runs
+                // against them. Afterwards expiry is ignored, so replicas reach the same result
+                if !is_master_link {
+                    for key in lists::keys_of(&command) {
+                        expire::expire_if_needed(&key, &redis).await;
+                    }
+                }
+
+                // Everything from the master is forwarded to sub-replicas, which has to stay in order
+                // with the snapshots they are served
+                let _write_guard = if command.is_write() || is_master_link {
+                    Some(write_lock.lock().await)
                 } else {
-                    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+                    None
+                };
+                // Read per command since REPLICAOF can change it at any time
+                let role = replication.lock().await.role;
+                // Only the master may change a replica's data, unless it is configured writable
+                if role == RedisState::Replica
+                    && command.is_write()
+                    && !is_master_link
+                    && config.replica_read_only
+                {
+                    handle_readonly(&mut output).await;
+                    continue;
+                }
+                if role == RedisState::Master
+                    && command.is_write()
+                    && !has_enough_good_replicas(&config, &replica_connections).await
+                {
+                    handle_noreplicas(&mut output).await;
                     continue;
                 }
+                if role == RedisState::Master && command.is_write() {
+                    write_commands_to_process += 1;
+                }
+                // Serialized before the command is consumed below, appended once it has been applied
+                let aof_entry = match &aof {
+                    Some(_) if command.is_write() => Some(aof::serialize_for_aof(&command)),
+                    _ => None,
+                };
 
                 // If command is write and this is the master, propagate command to all replicas
-                if config.role == RedisState::Master && command.is_write() {
-                    let replica_connections = replica_connections.read().await;
-                    if let Some(ref connections) = *replica_connections {
-                        for (_fd, replica_stream) in connections.iter() {
-                            synchronize::propagate_command_to_replica(
-                                Arc::clone(&replica_stream),
-                                &command,
-                            )
-                            .await;
-                        }
-                    }
+                if role == RedisState::Master && command.is_write() {
+                    last_write_offset = synchronize::propagate_to_replicas(
+                        &serialize_command(&command),
+                        Arc::clone(&replica_connections),
+                        Arc::clone(&replication),
+                    )
+                    .await;
                 }
 
                 match command {
                     Command::Echo(message) => {
-                        handle_echo(message, Arc::clone(&stream), config.role).await;
+                        handle_echo(message, &mut output, is_master_link).await;
                     }
                     Command::Ping => {
-                        handle_ping(Arc::clone(&stream), config.role).await;
+                        handle_ping(&mut output, is_master_link).await;
                     }
                     Command::Set(key, value, lifespan) => {
                         handle_set(
                             key,
                             value,
                             lifespan,
-                            Arc::clone(&stream),
+                            &mut output,
                             Arc::clone(&database),
                             Arc::clone(&expiry),
-                            config.role,
+                            is_master_link,
+                        )
+                        .await;
+                    }
+                    Command::Del(keys) => {
+                        handle_del(
+                            keys,
+                            &mut output,
+                            Arc::clone(&database),
+                            Arc::clone(&expiry),
+                            is_master_link,
                         )
                         .await;
                     }
                     Command::Get(key) => {
+                        expire::expire_if_needed(&key, &redis).await;
                         handle_get(
                             key,
-                            Arc::clone(&stream),
+                            &mut output,
                             Arc::clone(&database),
                             Arc::clone(&expiry),
+                            protocol,
                         )
                         .await;
                     }
+                    Command::Type(key) => {
+                        expire::expire_if_needed(&key, &redis).await;
+                        handle_type(key, &mut output, Arc::clone(&database), Arc::clone(&expiry))
+                            .await;
+                    }
                     Command::Info(arg) => {
-                        handle_info(arg, Arc::clone(&config), Arc::clone(&stream)).await;
+                        handle_info(
+                            arg,
+                            &mut output,
+                            Arc::clone(&replica_connections),
+                            Arc::clone(&replication),
+                            protocol,
+                        )
+                        .await;
+                    }
+                    Command::Hello(protocol_version, auth, client_name) => {
+                        protocol = handle_hello(
+                            protocol_version,
+                            auth,
+                            client_name,
+                            protocol,
+                            writer.id,
+                            &mut output,
+                            Arc::clone(&replication),
+                        )
+                        .await;
                     }
-                    Command::ReplConf(arg1, _arg2) => {
+                    Command::ReplConf(arg1, arg2) => {
                         match arg1.to_lowercase().as_str() {
-                            "getack" => {
-                                if config.role == RedisState::Master {
-                                    panic!("Recieving REPLCONF command as a master, should exclusively be sent by masters to replicas");
+                            "listening-port" => {
+                                listening_port = arg2.unwrap_or_default();
+                                replica::handle_replconf(&mut output).await;
+                            }
+                            "capa" => {
+                                capa_eof |= arg2.is_some_and(|x| x.eq_ignore_ascii_case("eof"));
+                                replica::handle_replconf(&mut output).await;
+                            }
+                            "ack" => {
+                                let ack_offset = arg2.and_then(|x| x.parse::<usize>().ok());
+                                if let (Some(id), Some(ack_offset)) = (replica_id, ack_offset) {
+                                    if let Some(replica) =
+                                        replica_connections.write().await.get_mut(&id)
+                                    {
+                                        replica.ack_offset = ack_offset;
+                                        replica.last_ack = Instant::now();
+                                    }
+                                    replica_acks.notify_waiters();
                                 }
-                                replica::handle_replconf_getack(
-                                    Arc::clone(&stream),
-                                    total_bytes_processed - 37,
-                                )
-                                .await;
                             }
-                            _ => replica::handle_replconf(Arc::clone(&stream)).await,
+                            // Only sent by masters to their replicas, anyone else just gets an OK
+                            "getack" if role == RedisState::Master => {
+                                replica::handle_replconf(&mut output).await;
+                            }
+                            "getack" => {
+                                // The offset acknowledged excludes the GETACK itself
+                                let offset = replication.lock().await.offset;
+                                replica::handle_replconf_getack(&mut output, offset).await;
+                            }
+                            _ => replica::handle_replconf(&mut output).await,
                         };
                     }
                     Command::Psync(replication_id, offset) => {
-                        if config.role == RedisState::Replica {
-                            panic!("Recieving PSYNC command as a replica, should exclusively be sent by replicas to masters");
-                        }
-                        replica::handle_psync(
+                        // No write may land between the snapshot and the replica being registered.
+                        // A replica serves its own snapshot, its replid and offsets are its master's
+                        let _write_guard = write_lock.lock().await;
+                        // The RDB is sent straight to the connection, after anything still pending
+                        flush_output(&writer, &mut output);
+                        let synced = replica::handle_psync(
                             replication_id,
                             offset,
-                            Arc::clone(&stream),
+                            &writer,
                             Arc::clone(&database),
+                            Arc::clone(&expiry),
+                            Arc::clone(&replication),
+                            config.repl_diskless_sync && capa_eof,
                         )
                         .await;
 
-                        use std::os::unix::io::AsRawFd;
-                        let mut guard = replica_connections.write().await;
-                        match *guard {
-                            Some(ref mut connections) => {
-                                let fd = stream.read().await.as_raw_fd();
-                                let _ = connections.insert(fd, Arc::clone(&stream));
-                            }
-                            None => panic!("Master should have a hashmap dedicated to storing connections to replicas"),
+                        let replica = ReplicaLink {
+                            writer: writer.clone(),
+                            ip: writer.peer_ip.clone(),
+                            listening_port: listening_port.clone(),
+                            ack_offset: 0,
+                            last_ack: Instant::now(),
+                        };
+                        replica_id = Some(writer.id);
+                        if synced {
+                            let _ = replica_connections.write().await.insert(writer.id, replica);
+                        } else {
+                            replica::schedule_diskless_sync(writer.id, replica, redis.clone())
+                                .await;
                         }
                     }
                     Command::Wait(replicas_to_wait_for, timeout) => {
-                        if config.role == RedisState::Replica {
-                            panic!(
-                                "Replica recieved WAIT command as replica - only meant for MASTER"
-                            );
+                        if role == RedisState::Replica {
+                            // A client of a replica, there are no replicas to wait for
+                            let response = serialize_resp_data(RespType::Error(String::from(
+                                "ERR WAIT cannot be used with replica instances",
+                            )));
+                            output.extend_from_slice(&response);
+                            continue;
                         }
+                        // Replies to earlier commands shouldn't wait along with this one
+                        flush_output(&writer, &mut output);
                         handle_wait(
                             Arc::clone(&replica_connections),
-                            Arc::clone(&stream),
+                            &mut output,
                             timeout,
                             replicas_to_wait_for,
-                            write_bytes_processed,
+                            last_write_offset,
                             write_commands_to_process,
+                            Arc::clone(&replication),
+                            Arc::clone(&write_lock),
+                            Arc::clone(&replica_acks),
                         )
                         .await;
                         write_commands_to_process = 0;
                     }
                     Command::ConfigGet(path_type) => {
-                        handle_config_get(Arc::clone(&stream), Arc::clone(&config), path_type)
+                        handle_config_get(&mut output, Arc::clone(&config), path_type, protocol)
                             .await;
                     }
                     Command::Keys(selector_arg) => {
-                        handle_keys(Arc::clone(&stream), Arc::clone(&database), selector_arg).await;
+                        handle_keys(&mut output, Arc::clone(&database), selector_arg).await;
+                    }
+                    Command::Save => {
+                        persistence::handle_save(
+                            &mut output,
+                            Arc::clone(&database),
+                            Arc::clone(&expiry),
+                            Arc::clone(&config),
+                            Arc::clone(&save_state),
+                        )
+                        .await;
+                    }
+                    Command::BgSave => {
+                        persistence::handle_bgsave(
+                            &mut output,
+                            Arc::clone(&database),
+                            Arc::clone(&expiry),
+                            Arc::clone(&config),
+                            Arc::clone(&save_state),
+                        )
+                        .await;
+                    }
+                    Command::BgRewriteAof => {
+                        persistence::handle_bgrewriteaof(
+                            &mut output,
+                            Arc::clone(&database),
+                            Arc::clone(&expiry),
+                            Arc::clone(&config),
+                            aof.clone(),
+                            Arc::clone(&write_lock),
+                        )
+                        .await;
+                    }
+                    Command::ReplicaOf(target) => {
+                        replica::handle_replicaof(target, &mut output, redis.clone()).await;
+                    }
+                    Command::Role => {
+                        replica::handle_role(
+                            &mut output,
+                            Arc::clone(&replica_connections),
+                            Arc::clone(&replication),
+                        )
+                        .await;
+                    }
+                    Command::RequestVote(epoch, replid, offset) => {
+                        failover::handle_requestvote(
+                            epoch,
+                            replid,
+                            offset,
+                            &mut output,
+                            Arc::clone(&replication),
+                        )
+                        .await;
+                    }
+                    Command::Sentinel(_, _) => {
+                        let response = serialize_resp_data(RespType::Error(String::from(
+                            "ERR SENTINEL is only available in sentinel mode",
+                        )));
+                        output.extend_from_slice(&response);
+                    }
+                    command @ (Command::LPush(_, _)
+                    | Command::RPush(_, _)
+                    | Command::LPop(_, _)
+                    | Command::RPop(_, _)
+                    | Command::LLen(_)
+                    | Command::LRange(_, _, _)
+                    | Command::LIndex(_, _)
+                    | Command::LSet(_, _, _)
+                    | Command::LRem(_, _, _)
+                    | Command::LTrim(_, _, _)
+                    | Command::LInsert(_, _, _, _)
+                    | Command::LPos(_, _, _, _, _)
+                    | Command::LMove(_, _, _, _)) => {
+                        lists::handle_list_command(
+                            command,
+                            &mut output,
+                            Arc::clone(&database),
+                            Arc::clone(&expiry),
+                            protocol,
+                            is_master_link,
+                        )
+                        .await;
+                    }
+                    Command::LastSave => {
+                        persistence::handle_lastsave(&mut output, Arc::clone(&save_state)).await;
                     }
                 };
+
+                if let (Some(aof), Some(entry)) = (&aof, aof_entry) {
+                    aof.append(&entry).await;
+                }
+
+                if is_master_link {
+                    synchronize::account_master_stream(
+                        &raw_command,
+                        Arc::clone(&replica_connections),
+                        Arc::clone(&replication),
+                    )
+                    .await;
+                }
             }
-        });
+            flush_output(&writer, &mut output);
+            // The other side is gone, so stop writing too. A replica still waiting for a diskless
+            // transfer then isn't registered as a dead link once it starts
+            writer.shutdown();
+            if let Some(id) = replica_id {
+                replica_connections.write().await.remove(&id);
+            }
+        })
     }
 
-    pub async fn listen(&mut self) -> Result<Self, Box<(dyn std::error::Error + 'static)>> {
-        match self.config.role {
-            RedisState::Replica => {
-                let parser = replica::perform_handshake(self).await;
-                let master_connection = {
-                    match &self.master_connection {
-                        Some(x) => Arc::clone(&x),
-                        None => panic!("Expected to have master connection on replica"),
-                    }
-                };
-                self.handle_conn(master_connection, Some(parser)).await;
-            }
-            RedisState::Master => (),
+    pub async fn listen(&mut self) -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        if self.config.role == RedisState::Replica {
+            replica::start_master_link(self).await;
         }
+        if self.config.auto_failover {
+            task::spawn(failover::monitor_master(self.clone()));
+        }
+        task::spawn(expire::active_expire(self.clone()));
+        // Only pings as a master with replicas, so it also covers instances promoted later
+        task::spawn(synchronize::ping_replicas(
+            Duration::from_secs(self.config.repl_ping_replica_period),
+            Arc::clone(&self.replica_connections),
+            Arc::clone(&self.replication),
+            Arc::clone(&self.write_lock),
+        ));
         loop {
             let (stream, _) = self.listener.accept().await?;
             println!("New stream connected to master: {:?}", stream);
-            let stream = Arc::new(RwLock::new(stream));
-            self.handle_conn(Arc::clone(&stream), None).await;
+            let (parser, writer) = split_connection(stream, self.config.client_output_buffer_limit);
+            self.handle_conn(parser, writer, false);
         }
     }
 
     pub async fn new(
         config: Arc<Config>,
         listener: TcpListener,
-    ) -> Result<Self, Box<(dyn std::error::Error + 'static)>> {
-        let connections: Arc<RwLock<Option<HashMap<i32, Arc<RwLock<TcpStream>>>>>> =
-            match config.role {
-                RedisState::Master => Arc::new(RwLock::new(Some(HashMap::new()))),
-                RedisState::Replica => Arc::new(RwLock::new(None)),
-            };
-        let mut database: Arc<Mutex<HashMap<String, String>>> =
-            Arc::new(Mutex::new(HashMap::new()));
-        let mut expiry: Arc<RwLock<HashMap<String, SystemTime>>> =
+    ) -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        let connections: ReplicaConnections = Arc::new(RwLock::new(HashMap::new()));
+        let mut database: Arc<Mutex<Keyspace>> = Arc::new(Mutex::new(Keyspace::new()));
+        let mut expiry: Arc<RwLock<HashMap<Bytes, SystemTime>>> =
             Arc::new(RwLock::new(HashMap::new()));
-        match (&config.rdb_dir, &config.rdb_filename) {
-            (Some(dir), Some(filename)) => {
-                let mut full_path = dir.clone();
-                full_path.push(filename);
-                match File::open(full_path).await {
-                    Ok(mut file) => {
-                        let mut contents = vec![];
-                        let _ = file.read_to_end(&mut contents).await;
-                        // Here we will parse the RDB file which returns a database
-                        let mut rdb_parser = RdbParser::new(contents);
-                        let (data_map, expiry_map) = rdb_parser.rdb_to_db();
+        let aof_path = config.aof_path();
+        let aof_exists = aof_path.exists();
+        // With AOF enabled the log is the authoritative copy of the data, so the RDB is skipped
+        if config.append_only && aof_exists {
+            let (data_map, expiry_map) = aof::load_aof(&aof_path).await?;
+            database = Arc::new(Mutex::new(data_map));
+            expiry = Arc::new(RwLock::new(expiry_map));
+        } else if config.rdb_dir.is_some() || config.rdb_filename.is_some() {
+            // Only an RDB the user pointed us at is loaded, a stray ./dump.rdb is left alone
+            if let Ok(mut file) = File::open(config.rdb_path()).await {
+                let mut contents = vec![];
+                let _ = file.read_to_end(&mut contents).await;
+                let mut rdb_parser = RdbParser::new(contents);
+                match rdb_parser.rdb_to_db() {
+                    Ok((data_map, expiry_map)) => {
                         database = Arc::new(Mutex::new(data_map));
                         expiry = Arc::new(RwLock::new(expiry_map));
                     }
-                    Err(_) => (),
-                };
+                    Err(e) => println!("Error loading RDB file, starting empty: {}", e),
+                }
             }
-            _ => (),
         }
 
+        let aof = if config.append_only {
+            let aof = Aof::open(&aof_path, config.aof_fsync).await?;
+            if !aof_exists {
+                // Seed a fresh log with the RDB contents so they survive the next restart
+                let seed = aof::rewrite_base(&*database.lock().await, &*expiry.read().await, false);
+                aof.append(&seed).await;
+            }
+            Some(aof)
+        } else {
+            None
+        };
+
+        let replication = Arc::new(Mutex::new(ReplicationState::new(&config)));
+
         Ok(Redis {
             database,
             expiry,
             config,
-            listener,
+            listener: Arc::new(listener),
             replica_connections: connections,
-            master_connection: None,
+            save_state: Arc::new(Mutex::new(SaveState::default())),
+            write_lock: Arc::new(Mutex::new(())),
+            aof,
+            replication,
+            master_link: Arc::new(Mutex::new(None)),
+            replica_acks: Arc::new(Notify::new()),
+            diskless_syncs: Arc::new(Mutex::new(vec![])),
         })
     }
 }
+
+// Returns false once the connection can no longer be written to
+fn flush_output(writer: &ConnectionWriter, output: &mut Vec<u8>) -> bool {
+    match output.is_empty() {
+        true => !writer.is_closed(),
+        false => writer.send(std::mem::take(output)),
+    }
+}
+
+// Good enough for ids, delays and sampling, not for anything security related. Every RandomState
+// is seeded with fresh random keys, so hashing nothing still gives a new value each time
+pub fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis::replication::generate_replid;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+    use tokio::time;
+
+    // Plays the master, then sends down the replication stream whatever the replica is given
+    async fn fake_master(listener: TcpListener) -> TcpStream {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 1024];
+        // PING, REPLCONF listening-port, REPLCONF capa psync2 and REPLCONF capa eof
+        for reply in ["+PONG\r\n", "+OK\r\n", "+OK\r\n", "+OK\r\n"] {
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream.write_all(reply.as_bytes()).await.unwrap();
+        }
+        let _ = stream.read(&mut buf).await.unwrap();
+        let (length, rdb) = construct_rdb(&Keyspace::new(), &HashMap::new());
+        let header = format!("+FULLRESYNC {} 0\r\n{}", generate_replid(), length);
+        stream.write_all(header.as_bytes()).await.unwrap();
+        stream.write_all(&rdb).await.unwrap();
+        stream
+    }
+
+    // Offsets the replica acknowledges until one reaches expected or it stops sending them
+    async fn wait_for_ack(stream: &mut TcpStream, expected: usize) -> Vec<usize> {
+        let mut acks = vec![];
+        let mut parser_input = Vec::new();
+        let mut buf = [0; 1024];
+        let deadline = time::Instant::now() + Duration::from_secs(5);
+        while !acks.contains(&expected) {
+            let n = match time::timeout_at(deadline, stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => n,
+                _ => break,
+            };
+            parser_input.extend_from_slice(&buf[..n]);
+            // REPLCONF ACK <offset>, the offset being the last line of each
+            let text = String::from_utf8_lossy(&parser_input).to_string();
+            acks = text
+                .split("*3\r\n")
+                .filter_map(|x| x.split("\r\n").nth(5)?.parse().ok())
+                .collect();
+        }
+        acks
+    }
+
+    #[tokio::test]
+    async fn commands_the_replica_rejects_still_count_towards_its_offset() {
+        let master_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let replica_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = Config {
+            port: replica_listener.local_addr().unwrap().port().to_string(),
+            role: RedisState::Replica,
+            master_host: Some(String::from("127.0.0.1")),
+            master_port: Some(master_listener.local_addr().unwrap().port().to_string()),
+            ..Config::default()
+        };
+        let mut replica = Redis::new(Arc::new(config), replica_listener)
+            .await
+            .unwrap();
+        task::spawn(async move {
+            let _ = replica.listen().await;
+        });
+
+        let mut master = fake_master(master_listener).await;
+        let stream: &[&[u8]] = &[
+            b"*2\r\n$10\r\nNEWCOMMAND\r\n$3\r\narg\r\n",
+            b"*2\r\n$3\r\nSET\r\n$1\r\na\r\n",
+            b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n",
+        ];
+        for command in stream {
+            master.write_all(command).await.unwrap();
+        }
+        let expected = stream.iter().map(|x| x.len()).sum();
+        master
+            .write_all(b"*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n")
+            .await
+            .unwrap();
+        let acks = wait_for_ack(&mut master, expected).await;
+        assert!(
+            acks.contains(&expected),
+            "expected {}, got {:?}",
+            expected,
+            acks
+        );
+    }
+}
@@ -0,0 +1,343 @@
+use super::commands::{self, Command, SetExpiry};
+use super::keyspace::{Keyspace, Value};
+use super::lists;
+use super::processing::{apply_del, apply_set};
+
+use crate::rdb::{rdb_parser::RdbParser, rdb_writer::RdbWriter, Dataset, MAGIC};
+use crate::resp::{resp_serializer::serialize_command, RespType};
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tokio::task;
+use tokio::time::{self, Duration};
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum FsyncPolicy {
+    Always,
+    EverySec,
+    No,
+}
+
+struct AofState {
+    file: File,
+    // Writes made while a rewrite is running, appended to the new log right before the swap
+    rewrite_buffer: Option<Vec<u8>>,
+    // Identifies the latest rewrite, so one that was superseded never swaps in its stale base
+    rewrite_id: u64,
+}
+
+impl AofState {
+    fn begin_rewrite(&mut self) -> u64 {
+        self.rewrite_id += 1;
+        self.rewrite_buffer = Some(Vec::new());
+        self.rewrite_id
+    }
+}
+
+pub struct Aof {
+    state: Mutex<AofState>,
+    fsync: FsyncPolicy,
+    path: PathBuf,
+}
+
+impl Aof {
+    pub async fn open(path: &Path, fsync: FsyncPolicy) -> std::io::Result<Arc<Aof>> {
+        let file = open_for_append(path).await?;
+        let aof = Arc::new(Aof {
+            state: Mutex::new(AofState {
+                file,
+                rewrite_buffer: None,
+                rewrite_id: 0,
+            }),
+            fsync,
+            path: path.to_path_buf(),
+        });
+        if fsync == FsyncPolicy::EverySec {
+            let aof = Arc::clone(&aof);
+            task::spawn(async move {
+                let mut interval = time::interval(Duration::from_secs(1));
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = aof.state.lock().await.file.sync_data().await {
+                        println!("Failed to fsync AOF: {}", e);
+                    }
+                }
+            });
+        }
+        Ok(aof)
+    }
+
+    pub async fn append(&self, entry: &[u8]) {
+        let mut state = self.state.lock().await;
+        if let Some(ref mut buffer) = state.rewrite_buffer {
+            buffer.extend_from_slice(entry);
+        }
+        let file = &mut state.file;
+        if let Err(e) = file.write_all(entry).await {
+            println!("Failed to append to AOF: {}", e);
+            return;
+        }
+        if let Err(e) = file.flush().await {
+            println!("Failed to flush AOF: {}", e);
+        }
+        if self.fsync == FsyncPolicy::Always {
+            if let Err(e) = file.sync_data().await {
+                println!("Failed to fsync AOF: {}", e);
+            }
+        }
+    }
+
+    // Starts capturing new writes for a rewrite, returning its id or None if a rewrite is already
+    // running. The caller must snapshot the keyspace before any further write can be appended
+    pub async fn start_rewrite(&self) -> Option<u64> {
+        let mut state = self.state.lock().await;
+        if state.rewrite_buffer.is_some() {
+            return None;
+        }
+        Some(state.begin_rewrite())
+    }
+
+    // Like start_rewrite, but abandons a rewrite that is already running, for when the snapshot
+    // it was started from no longer describes the data
+    pub async fn restart_rewrite(&self) -> u64 {
+        self.state.lock().await.begin_rewrite()
+    }
+
+    // Writes the compacted base to a temporary file, then appends the writes captured since the
+    // snapshot and swaps it in while holding the log, so no write is lost or duplicated
+    pub async fn finish_rewrite(&self, base: Vec<u8>, id: u64) -> std::io::Result<()> {
+        let file_name = format!("temp-rewriteaof-{}-{}.aof", std::process::id(), id);
+        let temp_path = self.path.with_file_name(file_name);
+        let result = async {
+            let mut temp_file = File::create(&temp_path).await?;
+            temp_file.write_all(&base).await?;
+
+            let mut state = self.state.lock().await;
+            if state.rewrite_id != id {
+                return Err(Error::other("superseded by a newer rewrite"));
+            }
+            let buffer = state.rewrite_buffer.take().unwrap_or_default();
+            temp_file.write_all(&buffer).await?;
+            temp_file.sync_all().await?;
+            fs::rename(&temp_path, &self.path).await?;
+            state.file = open_for_append(&self.path).await?;
+            Ok(())
+        }
+        .await;
+        if result.is_err() {
+            let mut state = self.state.lock().await;
+            if state.rewrite_id == id {
+                state.rewrite_buffer = None;
+            }
+            let _ = fs::remove_file(&temp_path).await;
+        }
+        result
+    }
+}
+
+async fn open_for_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+// Relative expiries are logged as absolute timestamps so replaying the log never extends a TTL
+pub fn serialize_for_aof(command: &Command) -> Vec<u8> {
+    match command {
+        Command::Set(key, value, Some(SetExpiry::After(lifespan))) => {
+            let expires_at = unix_millis(SystemTime::now()) + lifespan;
+            serialize_set_pxat(key, value, expires_at)
+        }
+        other => serialize_command(other),
+    }
+}
+
+// The minimal sequence of commands that recreates the given keyspace. None if it holds a type
+// the log can't replay yet, the keyspace then has to be written as an RDB preamble instead
+pub fn dataset_to_commands(
+    database: &Keyspace,
+    expiry: &HashMap<Bytes, SystemTime>,
+) -> Option<Vec<u8>> {
+    let now = SystemTime::now();
+    let mut commands = Vec::new();
+    for (key, value) in database.iter() {
+        let value = match value {
+            Value::String(x) => x,
+            // There's no command to log a list's expiry with, those need the RDB preamble
+            Value::List(list) if !expiry.contains_key(key) => {
+                // One RPUSH per quicklist node keeps each command reasonably small
+                for node in list.nodes() {
+                    let elements = node.iter().cloned().collect();
+                    commands.extend_from_slice(&serialize_command(&Command::RPush(
+                        key.clone(),
+                        elements,
+                    )));
+                }
+                continue;
+            }
+            _ => return None,
+        };
+        match expiry.get(key) {
+            Some(expiration) if *expiration <= now => (),
+            Some(expiration) => commands.extend_from_slice(&serialize_set_pxat(
+                key,
+                value,
+                unix_millis(*expiration),
+            )),
+            None => commands.extend_from_slice(&serialize_command(&Command::Set(
+                key.clone(),
+                value.clone(),
+                None,
+            ))),
+        }
+    }
+    Some(commands)
+}
+
+// What a rewritten log starts with, commands unless an RDB preamble is configured or needed
+pub fn rewrite_base(
+    database: &Keyspace,
+    expiry: &HashMap<Bytes, SystemTime>,
+    use_rdb_preamble: bool,
+) -> Vec<u8> {
+    let commands = match use_rdb_preamble {
+        true => None,
+        false => dataset_to_commands(database, expiry),
+    };
+    match commands {
+        Some(x) => x,
+        None => RdbWriter::new().db_to_rdb(database, expiry),
+    }
+}
+
+// Replays the log through the same command parsing used for live traffic, after loading the RDB
+// preamble if the log was rewritten with one. A command cut short by a crash is dropped and
+// truncated from the file so later appends start on a clean boundary
+pub async fn load_aof(path: &Path) -> std::io::Result<Dataset> {
+    let data = fs::read(path).await?;
+    let (mut database, mut expiry, mut index) = if data.starts_with(MAGIC) {
+        let mut rdb_parser = RdbParser::new(data.clone());
+        let (database, expiry) = rdb_parser
+            .rdb_to_db()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        println!(
+            "Loaded RDB preamble with {} keys from the AOF",
+            database.len()
+        );
+        (database, expiry, rdb_parser.bytes_parsed())
+    } else {
+        (Keyspace::new(), HashMap::new(), 0)
+    };
+    let mut commands_loaded = 0;
+    while index < data.len() {
+        let start = index;
+        let mut args = match parse_entry(&data, &mut index)? {
+            Some(x) => x,
+            None => {
+                println!(
+                    "AOF ends with a truncated command, discarding the last {} bytes",
+                    data.len() - start
+                );
+                let file = OpenOptions::new().write(true).open(path).await?;
+                file.set_len(start as u64).await?;
+                break;
+            }
+        };
+        let command_name = String::from_utf8_lossy(&args.remove(0)).to_string();
+        let args: Vec<RespType> = args
+            .into_iter()
+            .map(|x| RespType::BulkString(Some(x)))
+            .collect();
+        let command = commands::args_to_command(&command_name, args)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        replay_command(command, &mut database, &mut expiry);
+        commands_loaded += 1;
+    }
+    println!("Loaded {} commands from the AOF", commands_loaded);
+    Ok((database, expiry))
+}
+
+fn replay_command(
+    command: Command,
+    database: &mut Keyspace,
+    expiry: &mut HashMap<Bytes, SystemTime>,
+) {
+    match command {
+        Command::Set(key, value, lifespan) => apply_set(key, value, lifespan, database, expiry),
+        Command::Del(keys) => {
+            apply_del(&keys, database, expiry);
+        }
+        // Failed the same way when it was first run, so there is nothing to replay
+        command if lists::is_list_command(&command) => {
+            let _ = lists::apply_list_command(command, database, expiry);
+        }
+        other => println!("Ignoring non-write command in AOF: {:?}", other),
+    }
+}
+
+// Returns None if the data ends partway through the entry
+fn parse_entry(data: &[u8], index: &mut usize) -> std::io::Result<Option<Vec<Bytes>>> {
+    let num_args = match read_line(data, index) {
+        Some(line) => parse_header(line, b'*')?,
+        None => return Ok(None),
+    };
+    let mut args = Vec::with_capacity(num_args);
+    for _ in 0..num_args {
+        let length = match read_line(data, index) {
+            Some(line) => parse_header(line, b'$')?,
+            None => return Ok(None),
+        };
+        if *index + length + 2 > data.len() {
+            return Ok(None);
+        }
+        args.push(Bytes::copy_from_slice(&data[*index..*index + length]));
+        *index += length + 2;
+    }
+    if args.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "Empty command in AOF"));
+    }
+    Ok(Some(args))
+}
+
+fn read_line<'a>(data: &'a [u8], index: &mut usize) -> Option<&'a [u8]> {
+    let crlf_index = data[*index..].windows(2).position(|x| x == b"\r\n")?;
+    let line = &data[*index..*index + crlf_index];
+    *index += crlf_index + 2;
+    Some(line)
+}
+
+fn parse_header(line: &[u8], prefix: u8) -> std::io::Result<usize> {
+    match line.split_first() {
+        Some((first, rest)) if *first == prefix => std::str::from_utf8(rest)
+            .ok()
+            .and_then(|x| x.parse::<usize>().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid length in AOF")),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Expected '{}' in AOF", prefix as char),
+        )),
+    }
+}
+
+fn serialize_set_pxat(key: &Bytes, value: &Bytes, expires_at: u64) -> Vec<u8> {
+    serialize_command(&Command::Set(
+        key.clone(),
+        value.clone(),
+        Some(SetExpiry::At(expires_at)),
+    ))
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or(0)
+}
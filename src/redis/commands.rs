@@ -1,30 +1,129 @@
 use crate::resp::RespType;
 
+use bytes::Bytes;
+
+use thiserror::Error;
+
+// When a key written by SET expires, both are in milliseconds
+#[derive(Debug, Clone, Copy)]
+pub enum SetExpiry {
+    // PX, relative to now
+    After(u64),
+    // PXAT, an absolute unix time. Kept as is so a deadline in the past is never turned into PX 0
+    At(u64),
+}
+
 #[derive(Debug)]
 pub enum Command {
     Ping,
-    Echo(String),
-    Set(String, String, Option<u64>),
-    Get(String),
+    Echo(Bytes),
+    Set(Bytes, Bytes, Option<SetExpiry>),
+    Get(Bytes),
     Info(String),
     ReplConf(String, Option<String>),
     Psync(String, String),
     Wait(i32, i32),
     ConfigGet(String),
     Keys(String),
+    Save,
+    BgSave,
+    LastSave,
+    BgRewriteAof,
+    // Host and port of the new master, None for REPLICAOF NO ONE
+    ReplicaOf(Option<(String, String)>),
+    Role,
+    // Epoch, replication id and offset of a replica asking to be elected master
+    RequestVote(u64, String, usize),
+    // Subcommand and its arguments, only served in sentinel mode
+    Sentinel(String, Vec<String>),
+    // Also what a master propagates when one of its keys expires
+    Del(Vec<Bytes>),
+    // Protocol version, AUTH username and password, and SETNAME client name
+    Hello(Option<i64>, Option<(String, String)>, Option<String>),
+    Type(Bytes),
+    // Key and the elements to push, in the order they are pushed
+    LPush(Bytes, Vec<Bytes>),
+    RPush(Bytes, Vec<Bytes>),
+    // Key and how many elements to pop, None to pop a single one without an array reply
+    LPop(Bytes, Option<usize>),
+    RPop(Bytes, Option<usize>),
+    LLen(Bytes),
+    // Key, start and stop, negative indexes count from the tail
+    LRange(Bytes, i64, i64),
+    LIndex(Bytes, i64),
+    LSet(Bytes, i64, Bytes),
+    // Key, how many matches to remove and from which end, and the element to match
+    LRem(Bytes, i64, Bytes),
+    LTrim(Bytes, i64, i64),
+    // Key, whether to insert before the pivot rather than after it, pivot and element
+    LInsert(Bytes, bool, Bytes, Bytes),
+    // Key, element, RANK, COUNT (None when not given) and MAXLEN (0 for no limit)
+    LPos(Bytes, Bytes, i64, Option<usize>, usize),
+    // Source, destination, the end popped from and the end pushed to
+    LMove(Bytes, Bytes, ListEnd, ListEnd),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListEnd {
+    Left,
+    Right,
 }
 
 impl Command {
     pub fn is_write(&self) -> bool {
-        match self {
-            Command::Set(_, _, _) => true,
-            _ => false,
-        }
+        matches!(
+            self,
+            Command::Set(_, _, _)
+                | Command::Del(_)
+                | Command::LPush(_, _)
+                | Command::RPush(_, _)
+                | Command::LPop(_, _)
+                | Command::RPop(_, _)
+                | Command::LSet(_, _, _)
+                | Command::LRem(_, _, _)
+                | Command::LTrim(_, _, _)
+                | Command::LInsert(_, _, _, _)
+                | Command::LMove(_, _, _, _)
+        )
     }
 }
 
+// Sent back to the client as an error reply, the message starts with the error code
+#[derive(Error, Debug)]
+pub enum CommandError {
+    #[error("ERR unknown command '{0}', with args beginning with: {1}")]
+    UnknownCommand(String, String),
+    #[error("ERR unknown subcommand '{1}'. Try {0} HELP.")]
+    UnknownSubcommand(String, String),
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    WrongArity(String),
+    #[error("ERR value is not an integer or out of range")]
+    NotAnInteger,
+    #[error("ERR invalid expire time in '{0}' command")]
+    InvalidExpireTime(String),
+    #[error("ERR Protocol version is not an integer or out of range")]
+    InvalidProtocolVersion,
+    #[error("ERR syntax error")]
+    Syntax,
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("ERR value is out of range, must be positive")]
+    NotPositive,
+    #[error("ERR {0} can't be negative")]
+    Negative(String),
+    #[error("ERR RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list")]
+    ZeroRank,
+    #[error("ERR no such key")]
+    NoSuchKey,
+    #[error("ERR index out of range")]
+    IndexOutOfRange,
+    // The request couldn't be framed, the connection is closed after replying
+    #[error("ERR Protocol error: {0}")]
+    Protocol(String),
+}
+
 // Public
-pub fn args_to_command(command_name: &str, args: Vec<RespType>) -> Command {
+pub fn args_to_command(command_name: &str, args: Vec<RespType>) -> Result<Command, CommandError> {
     match command_name.to_lowercase().as_str() {
         "echo" => create_echo(args),
         "ping" => create_ping(args),
@@ -36,173 +135,418 @@ pub fn args_to_command(command_name: &str, args: Vec<RespType>) -> Command {
         "wait" => create_wait(args),
         "config" => create_config(args),
         "keys" => create_key(args),
-        other @ _ => panic!("No support for command type: {}", other),
+        "save" => create_save(args),
+        "bgsave" => create_bgsave(args),
+        "lastsave" => create_lastsave(args),
+        "bgrewriteaof" => create_bgrewriteaof(args),
+        "replicaof" | "slaveof" => create_replicaof(args),
+        "role" => create_role(args),
+        "requestvote" => create_requestvote(args),
+        "sentinel" => create_sentinel(args),
+        "del" | "unlink" => create_del(args),
+        "hello" => create_hello(args),
+        "type" => create_type(args),
+        "lpush" => create_push("lpush", args),
+        "rpush" => create_push("rpush", args),
+        "lpop" => create_pop("lpop", args),
+        "rpop" => create_pop("rpop", args),
+        "llen" => create_llen(args),
+        "lrange" => create_lrange(args),
+        "lindex" => create_lindex(args),
+        "lset" => create_lset(args),
+        "lrem" => create_lrem(args),
+        "ltrim" => create_ltrim(args),
+        "linsert" => create_linsert(args),
+        "lpos" => create_lpos(args),
+        "lmove" => create_lmove(args),
+        _ => {
+            let args_beginning: String = args
+                .iter()
+                .filter_map(|x| turn_arg_to_string(x).ok())
+                .map(|x| format!("'{}' ", x))
+                .collect();
+            Err(CommandError::UnknownCommand(
+                command_name.to_string(),
+                args_beginning,
+            ))
+        }
     }
 }
 
 // Private
-fn turn_arg_to_string(arg: &RespType) -> Option<String> {
+// For arguments that are text, such as options and numbers
+fn turn_arg_to_string(arg: &RespType) -> Result<String, CommandError> {
     match arg {
-        RespType::BulkString(x) => Some(String::from(x.as_ref().unwrap())),
-        RespType::SimpleString(x) => Some(String::from(x)),
-        _ => None,
+        RespType::BulkString(Some(x)) => Ok(String::from_utf8_lossy(x).to_string()),
+        RespType::SimpleString(x) => Ok(String::from(x)),
+        _ => Err(CommandError::Syntax),
     }
 }
 
-fn create_set(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        2 | 4 => (),
-        _ => panic!("Number of args for SET is wrong"),
+// For keys and values, which are kept exactly as sent
+fn turn_arg_to_bytes(arg: &RespType) -> Result<Bytes, CommandError> {
+    match arg {
+        RespType::BulkString(Some(x)) => Ok(x.clone()),
+        RespType::SimpleString(x) => Ok(Bytes::from(x.clone())),
+        _ => Err(CommandError::Syntax),
     }
+}
 
-    let mut string_args = Vec::new();
-    for arg in args.iter().take(2) {
-        match turn_arg_to_string(arg) {
-            Some(x) => string_args.push(x),
-            None => panic!("First two arguments for SET need to be strings"),
-        }
+fn turn_arg_to_number<T: std::str::FromStr>(arg: &RespType) -> Result<T, CommandError> {
+    turn_arg_to_string(arg)?
+        .parse::<T>()
+        .map_err(|_| CommandError::NotAnInteger)
+}
+
+fn check_arity(name: &str, args: &[RespType], counts: &[usize]) -> Result<(), CommandError> {
+    match counts.contains(&args.len()) {
+        true => Ok(()),
+        false => Err(CommandError::WrongArity(String::from(name))),
     }
+}
+
+fn create_set(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("set", &args, &[2, 4])?;
+    let key = turn_arg_to_bytes(&args[0])?;
+    let value = turn_arg_to_bytes(&args[1])?;
     let optional_arg = if args.len() == 4 {
-        match turn_arg_to_string(&args[2]) {
-            Some(x) if x == "px" => (),
-            _ => panic!("Expected third argument to SET to be px"),
+        let option = turn_arg_to_string(&args[2])?.to_lowercase();
+        let time = turn_arg_to_number::<i64>(&args[3])?;
+        if time <= 0 {
+            return Err(CommandError::InvalidExpireTime(String::from("set")));
         }
-        match turn_arg_to_string(&args[3]) {
-            Some(x) => match x.parse::<u64>() {
-                Ok(val) => Some(val),
-                Err(e) => panic!("Failed to convert px argument of SET to u64: {}", e),
-            },
-            None => panic!("Expected px argument to be provided as a string"),
+        let time = time as u64;
+        match option.as_str() {
+            "px" => Some(SetExpiry::After(time)),
+            // The AOF logs expiries this way
+            "pxat" => Some(SetExpiry::At(time)),
+            _ => return Err(CommandError::Syntax),
         }
     } else {
         None
     };
 
-    Command::Set(string_args[0].clone(), string_args[1].clone(), optional_arg)
+    Ok(Command::Set(key, value, optional_arg))
 }
 
-fn create_get(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        1 => (),
-        _ => panic!("Number of arguments for GET is wrong"),
-    };
-    let arg_value = match turn_arg_to_string(&args[0]) {
-        Some(x) => x,
-        None => panic!("Expected GET argument to be a string"),
-    };
-    Command::Get(arg_value)
+fn create_get(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("get", &args, &[1])?;
+    Ok(Command::Get(turn_arg_to_bytes(&args[0])?))
 }
 
-fn create_echo(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        1 => (),
-        _ => panic!("Number of arguments for ECHO is wrong"),
-    };
-    let arg_value = match &args[0] {
-        RespType::BulkString(x) => String::from(x.as_ref().unwrap()),
-        _ => panic!("Expect echo command to have a bulkstring as an argument"),
-    };
-    Command::Echo(arg_value)
+fn create_echo(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("echo", &args, &[1])?;
+    Ok(Command::Echo(turn_arg_to_bytes(&args[0])?))
 }
 
-fn create_replconf(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        1 | 2 => (),
-        _ => panic!("Number of arguments for REPLCONF is wrong"),
+fn create_replconf(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("replconf", &args, &[1, 2])?;
+    let arg1 = turn_arg_to_string(&args[0])?;
+    let optional_arg = match args.get(1) {
+        Some(x) => Some(turn_arg_to_string(x)?),
+        None => None,
     };
-    let arg1 = match turn_arg_to_string(&args[0]) {
-        Some(x) => x,
-        None => panic!("Expected first argument for REPLCONF to be a string"),
-    };
-    let optional_arg = if args.len() == 2 {
-        match turn_arg_to_string(&args[1]) {
-            Some(x) => Some(x),
-            None => panic!("Expected second argument for REPLCFONF to be a string"),
-        }
-    } else {
-        None
+    Ok(Command::ReplConf(arg1, optional_arg))
+}
+
+fn create_psync(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("psync", &args, &[2])?;
+    Ok(Command::Psync(
+        turn_arg_to_string(&args[0])?,
+        turn_arg_to_string(&args[1])?,
+    ))
+}
+
+fn create_ping(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("ping", &args, &[0])?;
+    Ok(Command::Ping)
+}
+
+fn create_info(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("info", &args, &[0, 1])?;
+    let section = match args.first() {
+        Some(x) => turn_arg_to_string(x)?,
+        None => String::from("default"),
     };
-    Command::ReplConf(arg1.clone(), optional_arg)
+    Ok(Command::Info(section))
+}
+
+fn create_wait(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("wait", &args, &[2])?;
+    Ok(Command::Wait(
+        turn_arg_to_number(&args[0])?,
+        turn_arg_to_number(&args[1])?,
+    ))
 }
 
-fn create_psync(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        2 => (),
-        _ => panic!("Number of arguments for PSYNC is wrong"),
+fn create_config(args: Vec<RespType>) -> Result<Command, CommandError> {
+    let subcommand = match args.first() {
+        Some(x) => turn_arg_to_string(x)?,
+        None => return Err(CommandError::WrongArity(String::from("config"))),
     };
-    let mut string_args = Vec::new();
-    for arg in args.iter().take(2) {
-        match turn_arg_to_string(arg) {
-            Some(x) => string_args.push(x),
-            None => panic!("First two arguments for SET need to be strings"),
-        }
+    if subcommand.to_lowercase() != "get" {
+        return Err(CommandError::UnknownSubcommand(
+            String::from("CONFIG"),
+            subcommand,
+        ));
     }
-    Command::Psync(string_args[0].clone(), string_args[1].clone())
+    check_arity("config|get", &args, &[2])?;
+    Ok(Command::ConfigGet(turn_arg_to_string(&args[1])?))
 }
 
-fn create_ping(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        0 => (),
-        _ => panic!("Number of arguments for PING is wrong"),
-    };
-    Command::Ping
+fn create_key(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("keys", &args, &[1])?;
+    Ok(Command::Keys(turn_arg_to_string(&args[0])?))
 }
 
-fn create_info(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        1 => (),
-        _ => panic!("Number of arguments for INFO is wrong"),
-    };
-    let arg_value = match turn_arg_to_string(&args[0]) {
-        Some(x) => x,
-        None => panic!("Expected first argument for INFO to be a string"),
-    };
-    Command::Info(arg_value)
+fn create_save(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("save", &args, &[0])?;
+    Ok(Command::Save)
+}
+
+fn create_bgsave(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("bgsave", &args, &[0])?;
+    Ok(Command::BgSave)
+}
+
+fn create_lastsave(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("lastsave", &args, &[0])?;
+    Ok(Command::LastSave)
+}
+
+fn create_bgrewriteaof(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("bgrewriteaof", &args, &[0])?;
+    Ok(Command::BgRewriteAof)
 }
 
-fn create_wait(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        2 => (),
-        _ => panic!("Number of arguments for WAIT is wrong"),
+fn create_replicaof(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("replicaof", &args, &[2])?;
+    let host = turn_arg_to_string(&args[0])?;
+    let port = turn_arg_to_string(&args[1])?;
+    if host.to_lowercase() == "no" && port.to_lowercase() == "one" {
+        return Ok(Command::ReplicaOf(None));
+    }
+    turn_arg_to_number::<u16>(&args[1])?;
+    Ok(Command::ReplicaOf(Some((host, port))))
+}
+
+fn create_role(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("role", &args, &[0])?;
+    Ok(Command::Role)
+}
+
+fn create_requestvote(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("requestvote", &args, &[3])?;
+    Ok(Command::RequestVote(
+        turn_arg_to_number(&args[0])?,
+        turn_arg_to_string(&args[1])?,
+        turn_arg_to_number(&args[2])?,
+    ))
+}
+
+fn create_sentinel(args: Vec<RespType>) -> Result<Command, CommandError> {
+    if args.is_empty() {
+        return Err(CommandError::WrongArity(String::from("sentinel")));
+    }
+    let mut string_args = args
+        .iter()
+        .map(turn_arg_to_string)
+        .collect::<Result<Vec<_>, _>>()?;
+    let subcommand = string_args.remove(0);
+    Ok(Command::Sentinel(subcommand, string_args))
+}
+
+fn create_del(args: Vec<RespType>) -> Result<Command, CommandError> {
+    if args.is_empty() {
+        return Err(CommandError::WrongArity(String::from("del")));
+    }
+    let keys = args
+        .iter()
+        .map(turn_arg_to_bytes)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Command::Del(keys))
+}
+
+fn create_hello(args: Vec<RespType>) -> Result<Command, CommandError> {
+    let mut string_args = args
+        .iter()
+        .map(turn_arg_to_string)
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter();
+    let protocol_version = match string_args.next() {
+        Some(x) => Some(
+            x.parse::<i64>()
+                .map_err(|_| CommandError::InvalidProtocolVersion)?,
+        ),
+        None => None,
     };
-    let mut arg_values: Vec<i32> = Vec::new();
-    for arg in args {
-        match turn_arg_to_string(&arg) {
-            Some(x) => match x.parse::<i32>() {
-                Ok(x) => arg_values.push(x),
-                Err(_) => panic!("Could not parse WAIT arguments to i32"),
+    let mut auth = None;
+    let mut client_name = None;
+    while let Some(option) = string_args.next() {
+        match option.to_lowercase().as_str() {
+            "auth" => match (string_args.next(), string_args.next()) {
+                (Some(username), Some(password)) => auth = Some((username, password)),
+                _ => return Err(CommandError::Syntax),
+            },
+            "setname" => match string_args.next() {
+                Some(name) => client_name = Some(name),
+                None => return Err(CommandError::Syntax),
             },
-            None => panic!("Arguments to WAIT should be strings"),
+            _ => return Err(CommandError::Syntax),
         }
     }
-    Command::Wait(arg_values[0].clone(), arg_values[1].clone())
+    Ok(Command::Hello(protocol_version, auth, client_name))
 }
 
-fn create_config(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        2 => (),
-        _ => panic!("Number of arguments for CONFIG GET is wrong"),
+fn create_type(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("type", &args, &[1])?;
+    Ok(Command::Type(turn_arg_to_bytes(&args[0])?))
+}
+
+fn create_push(name: &str, args: Vec<RespType>) -> Result<Command, CommandError> {
+    if args.len() < 2 {
+        return Err(CommandError::WrongArity(String::from(name)));
+    }
+    let key = turn_arg_to_bytes(&args[0])?;
+    let elements = args[1..]
+        .iter()
+        .map(turn_arg_to_bytes)
+        .collect::<Result<Vec<_>, _>>()?;
+    match name {
+        "lpush" => Ok(Command::LPush(key, elements)),
+        _ => Ok(Command::RPush(key, elements)),
     }
-    match turn_arg_to_string(&args[0]) {
-        Some(x) if x.as_str().to_lowercase() == "get" => (),
-        _ => panic!("Expected CONFIG to be followed by GET"),
+}
+
+fn create_pop(name: &str, args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity(name, &args, &[1, 2])?;
+    let key = turn_arg_to_bytes(&args[0])?;
+    let count = match args.get(1) {
+        Some(x) => Some(turn_arg_to_number::<usize>(x).map_err(|_| CommandError::NotPositive)?),
+        None => None,
     };
-    let arg_value = match turn_arg_to_string(&args[1]) {
-        Some(x) => x,
-        None => panic!("Expected argument for CONFIG GET to be a string"),
+    match name {
+        "lpop" => Ok(Command::LPop(key, count)),
+        _ => Ok(Command::RPop(key, count)),
+    }
+}
+
+fn create_llen(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("llen", &args, &[1])?;
+    Ok(Command::LLen(turn_arg_to_bytes(&args[0])?))
+}
+
+fn create_lrange(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("lrange", &args, &[3])?;
+    Ok(Command::LRange(
+        turn_arg_to_bytes(&args[0])?,
+        turn_arg_to_number(&args[1])?,
+        turn_arg_to_number(&args[2])?,
+    ))
+}
+
+fn create_lindex(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("lindex", &args, &[2])?;
+    Ok(Command::LIndex(
+        turn_arg_to_bytes(&args[0])?,
+        turn_arg_to_number(&args[1])?,
+    ))
+}
+
+fn create_lset(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("lset", &args, &[3])?;
+    Ok(Command::LSet(
+        turn_arg_to_bytes(&args[0])?,
+        turn_arg_to_number(&args[1])?,
+        turn_arg_to_bytes(&args[2])?,
+    ))
+}
+
+fn create_lrem(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("lrem", &args, &[3])?;
+    Ok(Command::LRem(
+        turn_arg_to_bytes(&args[0])?,
+        turn_arg_to_number(&args[1])?,
+        turn_arg_to_bytes(&args[2])?,
+    ))
+}
+
+fn create_ltrim(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("ltrim", &args, &[3])?;
+    Ok(Command::LTrim(
+        turn_arg_to_bytes(&args[0])?,
+        turn_arg_to_number(&args[1])?,
+        turn_arg_to_number(&args[2])?,
+    ))
+}
+
+fn create_linsert(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("linsert", &args, &[4])?;
+    let before = match turn_arg_to_string(&args[1])?.to_lowercase().as_str() {
+        "before" => true,
+        "after" => false,
+        _ => return Err(CommandError::Syntax),
     };
+    Ok(Command::LInsert(
+        turn_arg_to_bytes(&args[0])?,
+        before,
+        turn_arg_to_bytes(&args[2])?,
+        turn_arg_to_bytes(&args[3])?,
+    ))
+}
 
-    Command::ConfigGet(arg_value)
+fn create_lpos(args: Vec<RespType>) -> Result<Command, CommandError> {
+    if args.len() < 2 {
+        return Err(CommandError::WrongArity(String::from("lpos")));
+    }
+    let key = turn_arg_to_bytes(&args[0])?;
+    let element = turn_arg_to_bytes(&args[1])?;
+    let mut rank = 1;
+    let mut count = None;
+    let mut maxlen = 0;
+    for option in args[2..].chunks(2) {
+        if option.len() < 2 {
+            return Err(CommandError::Syntax);
+        }
+        match turn_arg_to_string(&option[0])?.to_lowercase().as_str() {
+            "rank" => {
+                rank = turn_arg_to_number::<i64>(&option[1])?;
+                // Negating the lowest value would overflow, Redis rejects it the same way
+                if rank == i64::MIN {
+                    return Err(CommandError::NotAnInteger);
+                }
+                if rank == 0 {
+                    return Err(CommandError::ZeroRank);
+                }
+            }
+            "count" => match turn_arg_to_number::<i64>(&option[1])? {
+                x if x < 0 => return Err(CommandError::Negative(String::from("COUNT"))),
+                x => count = Some(x as usize),
+            },
+            "maxlen" => match turn_arg_to_number::<i64>(&option[1])? {
+                x if x < 0 => return Err(CommandError::Negative(String::from("MAXLEN"))),
+                x => maxlen = x as usize,
+            },
+            _ => return Err(CommandError::Syntax),
+        }
+    }
+    Ok(Command::LPos(key, element, rank, count, maxlen))
 }
 
-fn create_key(args: Vec<RespType>) -> Command {
-    match &args.len() {
-        1 => (),
-        _ => panic!("Number of arguments for KEYS is wrong"),
+fn create_lmove(args: Vec<RespType>) -> Result<Command, CommandError> {
+    check_arity("lmove", &args, &[4])?;
+    Ok(Command::LMove(
+        turn_arg_to_bytes(&args[0])?,
+        turn_arg_to_bytes(&args[1])?,
+        turn_arg_to_list_end(&args[2])?,
+        turn_arg_to_list_end(&args[3])?,
+    ))
+}
+
+fn turn_arg_to_list_end(arg: &RespType) -> Result<ListEnd, CommandError> {
+    match turn_arg_to_string(arg)?.to_lowercase().as_str() {
+        "left" => Ok(ListEnd::Left),
+        "right" => Ok(ListEnd::Right),
+        _ => Err(CommandError::Syntax),
     }
-    let arg_value = match turn_arg_to_string(&args[0]) {
-        Some(x) => x,
-        None => panic!("Expected argument for KEYS to be a string"),
-    };
-    Command::Keys(arg_value)
 }
@@ -0,0 +1,145 @@
+use crate::resp::resp_deserializer::RespParser;
+
+use bytes::Bytes;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+use tokio::task;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+enum Outgoing {
+    Data(Bytes),
+    // Written out in full before anything sent after it, lets a large payload be produced while
+    // it is being sent
+    Stream(mpsc::Receiver<Bytes>),
+    Shutdown,
+}
+
+// Shared by every clone of a writer and its writer task
+#[derive(Default)]
+struct WriterState {
+    // Set on shutdown, so clones held elsewhere stop accepting data right away
+    closed: AtomicBool,
+    // Bytes sent but not written to the socket yet
+    queued: AtomicUsize,
+    // Signalled once the output limit is exceeded, the connection is dropped without flushing
+    overflowed: Notify,
+}
+
+// Sending side of a connection. Every clone feeds the same writer task, so replies, the
+// replication stream and ACKs go out in the order they were sent without anyone locking the socket
+#[derive(Clone)]
+pub struct ConnectionWriter {
+    // Unlike the file descriptor this is never reused, so it also serves as the client id
+    pub id: u64,
+    pub peer_ip: String,
+    sender: UnboundedSender<Outgoing>,
+    state: Arc<WriterState>,
+    // Most bytes that may wait to be written, like client-output-buffer-limit. 0 for no limit
+    output_limit: usize,
+}
+
+impl ConnectionWriter {
+    // Returns false once the connection can no longer be written to. A peer that doesn't read
+    // what it is sent fast enough gets dropped rather than having its output pile up in memory
+    pub fn send(&self, data: impl Into<Bytes>) -> bool {
+        if self.is_closed() {
+            return false;
+        }
+        let data = data.into();
+        let queued = self.state.queued.fetch_add(data.len(), Ordering::Relaxed) + data.len();
+        if self.output_limit != 0 && queued > self.output_limit {
+            println!(
+                "Connection {} exceeded the output buffer limit, dropping it",
+                self.id
+            );
+            self.state.closed.store(true, Ordering::Relaxed);
+            self.state.overflowed.notify_one();
+            return false;
+        }
+        self.sender.send(Outgoing::Data(data)).is_ok()
+    }
+
+    // Everything received on the stream goes out before whatever is sent after this call, which
+    // stays queued in the meantime. Returns false once the connection can no longer be written to
+    pub fn send_stream(&self, stream: mpsc::Receiver<Bytes>) -> bool {
+        !self.is_closed() && self.sender.send(Outgoing::Stream(stream)).is_ok()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.closed.load(Ordering::Relaxed) || self.sender.is_closed()
+    }
+
+    // Closes our side after whatever was sent before it, the reading side then sees it end too
+    pub fn shutdown(&self) {
+        self.state.closed.store(true, Ordering::Relaxed);
+        let _ = self.sender.send(Outgoing::Shutdown);
+    }
+}
+
+// Splits a connection into the parser reading from it and the writer sending to it
+pub fn split_connection(stream: TcpStream, output_limit: usize) -> (RespParser, ConnectionWriter) {
+    let peer_ip = stream
+        .peer_addr()
+        .map(|x| x.ip().to_string())
+        .unwrap_or_default();
+    let (read_half, write_half) = stream.into_split();
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let state = Arc::new(WriterState::default());
+    task::spawn(write_to_stream(write_half, receiver, Arc::clone(&state)));
+    let writer = ConnectionWriter {
+        id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+        peer_ip,
+        sender,
+        state,
+        output_limit,
+    };
+    (RespParser::new(read_half), writer)
+}
+
+// Runs until every writer is dropped, the connection is shut down, writing to it fails or it
+// goes over its output limit
+async fn write_to_stream(
+    mut write_half: OwnedWriteHalf,
+    mut receiver: UnboundedReceiver<Outgoing>,
+    state: Arc<WriterState>,
+) {
+    tokio::select! {
+        _ = write_messages(&mut write_half, &mut receiver, &state) => (),
+        // Even a write stuck on a peer that stopped reading is abandoned
+        _ = state.overflowed.notified() => (),
+    }
+    let _ = write_half.shutdown().await;
+}
+
+async fn write_messages(
+    write_half: &mut OwnedWriteHalf,
+    receiver: &mut UnboundedReceiver<Outgoing>,
+    state: &WriterState,
+) {
+    while let Some(message) = receiver.recv().await {
+        match message {
+            Outgoing::Data(data) => {
+                if let Err(e) = write_half.write_all(&data).await {
+                    println!("Failed to write to stream: {}", e);
+                    return;
+                }
+                state.queued.fetch_sub(data.len(), Ordering::Relaxed);
+            }
+            Outgoing::Stream(mut stream) => {
+                while let Some(data) = stream.recv().await {
+                    if let Err(e) = write_half.write_all(&data).await {
+                        println!("Failed to write to stream: {}", e);
+                        return;
+                    }
+                }
+            }
+            Outgoing::Shutdown => return,
+        }
+    }
+}
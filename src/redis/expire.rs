@@ -0,0 +1,112 @@
+use super::commands::Command;
+use super::synchronize::propagate_to_replicas;
+use super::{random_u64, Redis, RedisState};
+use crate::resp::resp_serializer::serialize_command;
+
+use bytes::Bytes;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::time::{self, Duration, Instant};
+
+const ACTIVE_EXPIRE_PERIOD: Duration = Duration::from_millis(100);
+// Like Redis, every round looks at a small random sample of the keys with an expiry, and another
+// round follows right away while more than a quarter of the sample turned out to be expired
+const KEYS_PER_ROUND: usize = 20;
+const REPEAT_EXPIRED_PERCENT: usize = 25;
+// Upper bound on how long one cycle keeps going
+const CYCLE_TIME_LIMIT: Duration = Duration::from_millis(25);
+
+// Only a master deletes expired keys. Replicas hide them from reads and wait for the DEL, so
+// their data never depends on their own clock
+pub async fn active_expire(redis: Redis) {
+    let mut interval = time::interval(ACTIVE_EXPIRE_PERIOD);
+    loop {
+        interval.tick().await;
+        if redis.replication.lock().await.role != RedisState::Master {
+            continue;
+        }
+        let started = Instant::now();
+        loop {
+            let (sampled, expired) = expire_sample(&redis).await;
+            if expired * 100 <= sampled * REPEAT_EXPIRED_PERCENT
+                || started.elapsed() >= CYCLE_TIME_LIMIT
+            {
+                break;
+            }
+        }
+    }
+}
+
+// Called before a master reads a key, so one that expired between two active cycles is deleted
+// right away rather than only hidden
+pub async fn expire_if_needed(key: &Bytes, redis: &Redis) {
+    // Checked again under the write lock, the key may have been rewritten in between
+    if !is_expired(key, redis).await {
+        return;
+    }
+    let _write_guard = redis.write_lock.lock().await;
+    if redis.replication.lock().await.role == RedisState::Master && is_expired(key, redis).await {
+        delete_expired_key(key.clone(), redis).await;
+    }
+}
+
+// Deletes the expired keys among a random sample, returning how many keys were sampled and how
+// many of them were deleted
+async fn expire_sample(redis: &Redis) -> (usize, usize) {
+    let _write_guard = redis.write_lock.lock().await;
+    // The role may have changed while waiting for the lock
+    if redis.replication.lock().await.role != RedisState::Master {
+        return (0, 0);
+    }
+    let now = SystemTime::now();
+    let (sampled, expired) = {
+        let expiry = redis.expiry.read().await;
+        let sampled = expiry.len().min(KEYS_PER_ROUND);
+        // Starts at a random key and wraps around, so every key gets looked at eventually
+        let start = random_index(expiry.len());
+        let expired: Vec<Bytes> = expiry
+            .iter()
+            .skip(start)
+            .chain(expiry.iter())
+            .take(sampled)
+            .filter(|(_, expiration)| now > **expiration)
+            .map(|(key, _)| key.clone())
+            .collect();
+        (sampled, expired)
+    };
+    let deleted = expired.len();
+    for key in expired {
+        delete_expired_key(key, redis).await;
+    }
+    (sampled, deleted)
+}
+
+// Below length, 0 for an empty map
+fn random_index(length: usize) -> usize {
+    (random_u64() % length.max(1) as u64) as usize
+}
+
+async fn is_expired(key: &Bytes, redis: &Redis) -> bool {
+    redis
+        .expiry
+        .read()
+        .await
+        .get(key)
+        .is_some_and(|x| SystemTime::now() > *x)
+}
+
+// Must be called with the write lock held, the DEL is part of the replication stream
+async fn delete_expired_key(key: Bytes, redis: &Redis) {
+    redis.database.lock().await.remove(&key);
+    redis.expiry.write().await.remove(&key);
+    let del = serialize_command(&Command::Del(vec![key]));
+    propagate_to_replicas(
+        &del,
+        Arc::clone(&redis.replica_connections),
+        Arc::clone(&redis.replication),
+    )
+    .await;
+    if let Some(aof) = &redis.aof {
+        aof.append(&del).await;
+    }
+}
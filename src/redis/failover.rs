@@ -0,0 +1,241 @@
+use super::replica::{replicaof, HandshakeError};
+use super::replication::{parse_master_role, Address, ReplicationState};
+use super::{random_u64, Redis, RedisState};
+use crate::resp::{resp_deserializer::RespParser, resp_serializer::serialize_resp_data, RespType};
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::{self, Duration};
+
+const HEARTBEAT_PERIOD: Duration = Duration::from_secs(1);
+// Upper bound for a single request to another instance, so an unreachable one can't stall the
+// election
+const PEER_TIMEOUT: Duration = Duration::from_millis(500);
+
+// Runs on every instance with auto failover enabled. As a replica it keeps track of the other
+// replicas of its master and starts an election once the master has been unreachable for the
+// failover timeout. As a master it points the instances that missed its promotion at itself
+pub async fn monitor_master(redis: Redis) {
+    let failover_timeout = Duration::from_millis(redis.config.failover_timeout);
+    let own_port = redis.config.port.clone();
+    // Replaced by the address the master sees once it has listed this replica
+    let mut own_host = String::from("127.0.0.1");
+    // Other replicas of the same master, as listed by the master's ROLE
+    let mut peers: Vec<Address> = vec![];
+    // Instances that haven't acknowledged this instance as their new master yet
+    let mut to_reconfigure: Vec<Address> = vec![];
+    let mut interval = time::interval(HEARTBEAT_PERIOD);
+    loop {
+        interval.tick().await;
+        let (role, master, link_up, last_io) = {
+            let replication = redis.replication.lock().await;
+            (
+                replication.role,
+                (
+                    replication.master_host.clone().unwrap_or_default(),
+                    replication.master_port.clone().unwrap_or_default(),
+                ),
+                replication.master_link_up,
+                replication.master_last_io,
+            )
+        };
+
+        if role == RedisState::Master {
+            let mut unreachable = vec![];
+            for address in to_reconfigure.drain(..) {
+                let request = ["REPLICAOF", own_host.as_str(), own_port.as_str()];
+                if query(&address, &request).await.is_err() {
+                    unreachable.push(address);
+                }
+            }
+            to_reconfigure = unreachable;
+            continue;
+        }
+        to_reconfigure.clear();
+
+        if link_up {
+            redis.replication.lock().await.peer_offset = 0;
+            if let Ok(reply) = query(&master, &["ROLE"]).await {
+                if let Some(replicas) = parse_master_role(&reply) {
+                    peers.clear();
+                    for (host, port) in replicas {
+                        if port == own_port {
+                            own_host = host;
+                        } else {
+                            peers.push((host, port));
+                        }
+                    }
+                }
+            }
+            continue;
+        }
+
+        // Kept up to date while the master is down, so neither this instance nor its vote goes to
+        // a replica that has seen less of the history than another one
+        let replid = redis.replication.lock().await.replid.clone();
+        let peer_offset = highest_peer_offset(&peers, &replid).await;
+        redis.replication.lock().await.peer_offset = peer_offset;
+
+        // Only a master that was reachable at some point can fail
+        match last_io {
+            Some(x) if x.elapsed() >= failover_timeout => (),
+            _ => continue,
+        }
+        // Spread candidates out so they rarely split the vote
+        time::sleep(random_delay(failover_timeout / 2)).await;
+        {
+            let replication = redis.replication.lock().await;
+            // Give a candidate we voted for time to reconfigure us before standing ourselves
+            let voted_recently = replication
+                .last_vote_at
+                .is_some_and(|x| x.elapsed() < failover_timeout);
+            if replication.role != RedisState::Replica
+                || replication.master_link_up
+                || replication.master_port.as_ref() != Some(&master.1)
+                || voted_recently
+                || replication.peer_offset > replication.offset
+            {
+                continue;
+            }
+        }
+        if run_election(&redis, &peers).await {
+            println!(
+                "Won the election, taking over from {}:{}",
+                master.0, master.1
+            );
+            replicaof(&redis, None).await;
+            to_reconfigure = peers.clone();
+            to_reconfigure.push(master);
+        }
+    }
+}
+
+pub async fn handle_requestvote(
+    epoch: u64,
+    replid: String,
+    offset: usize,
+    output: &mut Vec<u8>,
+    replication: Arc<Mutex<ReplicationState>>,
+) {
+    let granted = {
+        let mut replication = replication.lock().await;
+        replication.current_epoch = replication.current_epoch.max(epoch);
+        // One vote per epoch, and only for a candidate that has seen at least as much of the
+        // same history as this instance and every other replica it heard from, so the replica
+        // with the highest offset is the one that gets elected
+        let granted = replication.role == RedisState::Replica
+            && !replication.master_link_up
+            && epoch > replication.last_vote_epoch
+            && replid == replication.replid
+            && offset >= replication.offset
+            && offset >= replication.peer_offset;
+        if granted {
+            replication.last_vote_epoch = epoch;
+            replication.last_vote_at = Some(Instant::now());
+        }
+        granted
+    };
+    println!(
+        "Vote for epoch {} at offset {}: {}",
+        epoch,
+        offset,
+        if granted { "granted" } else { "refused" }
+    );
+    let response = serialize_resp_data(RespType::Integer(granted as i64));
+    output.extend_from_slice(&response);
+}
+
+// Asks every peer for its vote in a new epoch, true once a majority of the replicas, this one
+// included, has voted for it
+async fn run_election(redis: &Redis, peers: &[Address]) -> bool {
+    let (epoch, replid, offset) = {
+        let mut replication = redis.replication.lock().await;
+        let epoch = replication.current_epoch.max(replication.last_vote_epoch) + 1;
+        replication.current_epoch = epoch;
+        replication.last_vote_epoch = epoch;
+        (epoch, replication.replid.clone(), replication.offset)
+    };
+    println!(
+        "Master is unreachable, starting election for epoch {}",
+        epoch
+    );
+    let epoch = epoch.to_string();
+    let offset = offset.to_string();
+    let request = [
+        "REQUESTVOTE",
+        epoch.as_str(),
+        replid.as_str(),
+        offset.as_str(),
+    ];
+    let mut votes = 1;
+    for peer in peers {
+        if let Ok(reply) = query(peer, &request).await {
+            if matches!(reply, RespType::Integer(1)) {
+                votes += 1;
+            }
+        }
+    }
+    let replicas = peers.len() + 1;
+    let quorum = replicas / 2 + 1;
+    println!("Received {} of {} votes needed", votes, quorum);
+    votes >= quorum
+}
+
+// Sends a single command over a fresh connection and returns the whole reply
+pub async fn query(address: &Address, args: &[&str]) -> Result<RespType, HandshakeError> {
+    let command = serialize_resp_data(RespType::Array(
+        args.iter()
+            .map(|x| RespType::BulkString(Some(Bytes::from(x.to_string()))))
+            .collect(),
+    ));
+    let request = async {
+        let stream = TcpStream::connect(format!("{}:{}", address.0, address.1)).await?;
+        let (read_half, mut write_half) = stream.into_split();
+        write_half.write_all(&command).await?;
+        match RespParser::new(read_half).parse_reply().await {
+            Some(reply) => Ok(reply?),
+            None => Err("Connection closed before the reply arrived".into()),
+        }
+    };
+    time::timeout(PEER_TIMEOUT, request).await?
+}
+
+pub async fn info_replication(address: &Address) -> Option<HashMap<String, String>> {
+    let reply = match query(address, &["INFO", "replication"]).await.ok()? {
+        RespType::BulkString(Some(x)) => String::from_utf8_lossy(&x).to_string(),
+        _ => return None,
+    };
+    let info = reply
+        .lines()
+        .filter_map(|x| x.split_once(':'))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    Some(info)
+}
+
+// Highest offset among the peers that follow the same history, 0 if none of them answered
+async fn highest_peer_offset(peers: &[Address], replid: &str) -> usize {
+    let mut highest = 0;
+    for peer in peers {
+        let info = match info_replication(peer).await {
+            Some(x) if x.get("master_replid").map(String::as_str) == Some(replid) => x,
+            _ => continue,
+        };
+        if let Some(offset) = info
+            .get("master_repl_offset")
+            .and_then(|x| x.parse::<usize>().ok())
+        {
+            highest = highest.max(offset);
+        }
+    }
+    highest
+}
+
+fn random_delay(max: Duration) -> Duration {
+    Duration::from_millis(random_u64() % (max.as_millis() as u64 + 1))
+}
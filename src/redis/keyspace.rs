@@ -0,0 +1,208 @@
+use super::commands::CommandError;
+use super::quicklist::QuickList;
+
+use bytes::Bytes;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+// A value along with the encoding its data type is kept in
+#[derive(Clone, Debug)]
+pub enum Value {
+    String(Bytes),
+    List(QuickList),
+    Hash(HashMap<Bytes, Bytes>),
+    Set(HashSet<Bytes>),
+    SortedSet(SortedSet),
+}
+
+impl Value {
+    // As reported by TYPE
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+        }
+    }
+
+    // Only strings may be empty, the other types are deleted along with their last element
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            Value::String(_) => false,
+            Value::List(x) => x.is_empty(),
+            Value::Hash(x) => x.is_empty(),
+            Value::Set(x) => x.is_empty(),
+            Value::SortedSet(x) => x.is_empty(),
+        }
+    }
+}
+
+// Every key along with its value. Expiry is tracked separately, like Redis does, since most keys
+// don't have one
+#[derive(Clone, Debug, Default)]
+pub struct Keyspace {
+    entries: HashMap<Bytes, Value>,
+}
+
+impl Keyspace {
+    pub fn new() -> Keyspace {
+        Keyspace {
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: &Bytes) -> Option<&Value> {
+        self.entries.get(key)
+    }
+
+    // WRONGTYPE if the key holds anything but a string
+    pub fn get_string(&self, key: &Bytes) -> Result<Option<&Bytes>, CommandError> {
+        match self.entries.get(key) {
+            None => Ok(None),
+            Some(Value::String(x)) => Ok(Some(x)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    // WRONGTYPE if the key holds anything but a list
+    pub fn get_list(&self, key: &Bytes) -> Result<Option<&QuickList>, CommandError> {
+        match self.entries.get(key) {
+            None => Ok(None),
+            Some(Value::List(x)) => Ok(Some(x)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    pub fn get_list_mut(&mut self, key: &Bytes) -> Result<Option<&mut QuickList>, CommandError> {
+        match self.entries.get_mut(key) {
+            None => Ok(None),
+            Some(Value::List(x)) => Ok(Some(x)),
+            Some(_) => Err(CommandError::WrongType),
+        }
+    }
+
+    // Creates an empty list if the key doesn't exist yet
+    pub fn get_or_insert_list(&mut self, key: &Bytes) -> Result<&mut QuickList, CommandError> {
+        let value = self
+            .entries
+            .entry(key.clone())
+            .or_insert_with(|| Value::List(QuickList::new()));
+        match value {
+            Value::List(x) => Ok(x),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    // Replaces whatever the key held, whatever its type
+    pub fn insert(&mut self, key: Bytes, value: Value) {
+        self.entries.insert(key, value);
+    }
+
+    pub fn remove(&mut self, key: &Bytes) -> Option<Value> {
+        self.entries.remove(key)
+    }
+
+    pub fn contains_key(&self, key: &Bytes) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn type_of(&self, key: &Bytes) -> &'static str {
+        match self.entries.get(key) {
+            Some(x) => x.type_name(),
+            None => "none",
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Bytes> {
+        self.entries.keys()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &Value)> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.entries.reserve(additional);
+    }
+}
+
+// Members ordered by score and then lexicographically, with a lookup of each member's score
+#[derive(Clone, Debug, Default)]
+pub struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    ordered: BTreeSet<(Score, Bytes)>,
+}
+
+impl SortedSet {
+    pub fn new() -> SortedSet {
+        SortedSet::default()
+    }
+
+    // Adds the member or updates its score, returning true if it wasn't there before
+    pub fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(previous) = previous {
+            self.ordered.remove(&(Score(previous), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        previous.is_none()
+    }
+
+    pub fn remove(&mut self, member: &Bytes) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.ordered.remove(&(Score(score), member.clone())),
+            None => false,
+        }
+    }
+
+    pub fn score(&self, member: &Bytes) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    // Lowest score first
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, f64)> {
+        self.ordered.iter().map(|(score, member)| (member, score.0))
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+}
+
+// Scores are never NaN, which lets them be totally ordered
+#[derive(Clone, Copy, Debug)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
@@ -0,0 +1,368 @@
+use super::commands::{Command, CommandError, ListEnd};
+use super::keyspace::Keyspace;
+use super::processing::{handle_command_error, is_logically_expired};
+use super::quicklist::QuickList;
+
+use crate::resp::{resp_serializer::serialize_for_protocol, Protocol, RespType};
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::{Mutex, RwLock};
+
+// Keys a list command touches, empty for any other command
+pub fn keys_of(command: &Command) -> Vec<Bytes> {
+    match command {
+        Command::LPush(key, _)
+        | Command::RPush(key, _)
+        | Command::LPop(key, _)
+        | Command::RPop(key, _)
+        | Command::LLen(key)
+        | Command::LRange(key, _, _)
+        | Command::LIndex(key, _)
+        | Command::LSet(key, _, _)
+        | Command::LRem(key, _, _)
+        | Command::LTrim(key, _, _)
+        | Command::LInsert(key, _, _, _)
+        | Command::LPos(key, _, _, _, _) => vec![key.clone()],
+        Command::LMove(source, destination, _, _) => vec![source.clone(), destination.clone()],
+        _ => vec![],
+    }
+}
+
+pub fn is_list_command(command: &Command) -> bool {
+    !keys_of(command).is_empty()
+}
+
+pub async fn handle_list_command(
+    command: Command,
+    output: &mut Vec<u8>,
+    db: Arc<Mutex<Keyspace>>,
+    expiry: Arc<RwLock<HashMap<Bytes, SystemTime>>>,
+    protocol: Protocol,
+    from_master: bool,
+) {
+    let result = {
+        let mut db = db.lock().await;
+        let mut expiry = expiry.write().await;
+        // Replicas keep expired keys until the master's DEL arrives, reads see them as missing
+        let hide_expired = !command.is_write()
+            && keys_of(&command)
+                .iter()
+                .any(|x| is_logically_expired(x, &expiry));
+        match hide_expired {
+            true => apply_list_command(command, &mut Keyspace::new(), &mut HashMap::new()),
+            false => apply_list_command(command, &mut db, &mut expiry),
+        }
+    };
+    match result {
+        Ok(_) if from_master => (),
+        Ok(response) => output.extend_from_slice(&serialize_for_protocol(response, protocol)),
+        Err(e) => handle_command_error(e, output, from_master).await,
+    }
+}
+
+// Runs a list command and returns its reply, shared by live traffic and AOF replay. Expiry is
+// left to the caller, so the master, its replicas and the log all end up with the same list
+pub fn apply_list_command(
+    command: Command,
+    db: &mut Keyspace,
+    expiry: &mut HashMap<Bytes, SystemTime>,
+) -> Result<RespType, CommandError> {
+    match command {
+        Command::LPush(key, elements) => push(key, elements, ListEnd::Left, db),
+        Command::RPush(key, elements) => push(key, elements, ListEnd::Right, db),
+        Command::LPop(key, count) => pop(key, count, ListEnd::Left, db, expiry),
+        Command::RPop(key, count) => pop(key, count, ListEnd::Right, db, expiry),
+        Command::LLen(key) => {
+            let length = db.get_list(&key)?.map(|x| x.len()).unwrap_or(0);
+            Ok(RespType::Integer(length as i64))
+        }
+        Command::LRange(key, start, stop) => range(key, start, stop, db),
+        Command::LIndex(key, index) => {
+            let element = match db.get_list(&key)? {
+                Some(list) => normalize_index(index, list.len()).and_then(|x| list.get(x)),
+                None => None,
+            };
+            match element {
+                Some(x) => Ok(RespType::BulkString(Some(x.clone()))),
+                None => Ok(RespType::Null),
+            }
+        }
+        Command::LSet(key, index, element) => {
+            let list = db.get_list_mut(&key)?.ok_or(CommandError::NoSuchKey)?;
+            let index = normalize_index(index, list.len()).ok_or(CommandError::IndexOutOfRange)?;
+            list.set(index, element);
+            Ok(RespType::SimpleString(String::from("OK")))
+        }
+        Command::LRem(key, count, element) => remove(key, count, element, db, expiry),
+        Command::LTrim(key, start, stop) => trim(key, start, stop, db, expiry),
+        Command::LInsert(key, before, pivot, element) => {
+            let list = match db.get_list_mut(&key)? {
+                Some(x) => x,
+                None => return Ok(RespType::Integer(0)),
+            };
+            let index = match list.iter().position(|x| *x == pivot) {
+                Some(x) if before => x,
+                Some(x) => x + 1,
+                None => return Ok(RespType::Integer(-1)),
+            };
+            list.insert(index, element);
+            Ok(RespType::Integer(list.len() as i64))
+        }
+        Command::LPos(key, element, rank, count, maxlen) => {
+            position(key, element, rank, count, maxlen, db)
+        }
+        Command::LMove(source, destination, from, to) => {
+            move_element(source, destination, from, to, db, expiry)
+        }
+        other => unreachable!("{:?} is not a list command", other),
+    }
+}
+
+fn push(
+    key: Bytes,
+    elements: Vec<Bytes>,
+    end: ListEnd,
+    db: &mut Keyspace,
+) -> Result<RespType, CommandError> {
+    let list = db.get_or_insert_list(&key)?;
+    for element in elements {
+        push_to(list, end, element);
+    }
+    Ok(RespType::Integer(list.len() as i64))
+}
+
+fn pop(
+    key: Bytes,
+    count: Option<usize>,
+    end: ListEnd,
+    db: &mut Keyspace,
+    expiry: &mut HashMap<Bytes, SystemTime>,
+) -> Result<RespType, CommandError> {
+    let list = match db.get_list_mut(&key)? {
+        Some(x) => x,
+        None if count.is_some() => return Ok(RespType::NullArray),
+        None => return Ok(RespType::Null),
+    };
+    let response = match count {
+        None => RespType::BulkString(pop_from(list, end)),
+        Some(count) => RespType::Array(
+            (0..count)
+                .map_while(|_| pop_from(list, end))
+                .map(|x| RespType::BulkString(Some(x)))
+                .collect(),
+        ),
+    };
+    remove_if_empty(&key, db, expiry);
+    Ok(response)
+}
+
+fn range(key: Bytes, start: i64, stop: i64, db: &Keyspace) -> Result<RespType, CommandError> {
+    let list = match db.get_list(&key)? {
+        Some(x) => x,
+        None => return Ok(RespType::Array(vec![])),
+    };
+    let elements = match normalize_range(start, stop, list.len()) {
+        Some((start, stop)) => list
+            .iter()
+            .skip(start)
+            .take(stop - start + 1)
+            .map(|x| RespType::BulkString(Some(x.clone())))
+            .collect(),
+        None => vec![],
+    };
+    Ok(RespType::Array(elements))
+}
+
+// A positive count removes matches from the head, a negative one from the tail, zero removes all
+fn remove(
+    key: Bytes,
+    count: i64,
+    element: Bytes,
+    db: &mut Keyspace,
+    expiry: &mut HashMap<Bytes, SystemTime>,
+) -> Result<RespType, CommandError> {
+    let list = match db.get_list_mut(&key)? {
+        Some(x) => x,
+        None => return Ok(RespType::Integer(0)),
+    };
+    let mut matches: Vec<usize> = list
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| **x == element)
+        .map(|(index, _)| index)
+        .collect();
+    let limit = count.unsigned_abs() as usize;
+    if limit != 0 && matches.len() > limit {
+        match count > 0 {
+            true => matches.truncate(limit),
+            false => {
+                matches.drain(..matches.len() - limit);
+            }
+        }
+    }
+    // From the back, so the indexes still to be removed don't shift
+    for index in matches.iter().rev() {
+        list.remove(*index);
+    }
+    remove_if_empty(&key, db, expiry);
+    Ok(RespType::Integer(matches.len() as i64))
+}
+
+fn trim(
+    key: Bytes,
+    start: i64,
+    stop: i64,
+    db: &mut Keyspace,
+    expiry: &mut HashMap<Bytes, SystemTime>,
+) -> Result<RespType, CommandError> {
+    if let Some(list) = db.get_list_mut(&key)? {
+        let length = list.len();
+        match normalize_range(start, stop, length) {
+            Some((start, stop)) => {
+                for _ in stop + 1..length {
+                    list.pop_back();
+                }
+                for _ in 0..start {
+                    list.pop_front();
+                }
+            }
+            // No element falls within the range, so none are kept
+            None => while list.pop_back().is_some() {},
+        }
+        remove_if_empty(&key, db, expiry);
+    }
+    Ok(RespType::SimpleString(String::from("OK")))
+}
+
+// RANK picks which match to start from, negative ones searching from the tail. At most MAXLEN
+// elements are compared, and COUNT matches returned, all of them if it is 0
+fn position(
+    key: Bytes,
+    element: Bytes,
+    rank: i64,
+    count: Option<usize>,
+    maxlen: usize,
+    db: &Keyspace,
+) -> Result<RespType, CommandError> {
+    let list = db.get_list(&key)?;
+    let length = list.map(|x| x.len()).unwrap_or(0);
+    let elements: Box<dyn Iterator<Item = (usize, &Bytes)>> = match (list, rank > 0) {
+        (None, _) => Box::new(std::iter::empty()),
+        (Some(list), true) => Box::new(list.iter().enumerate()),
+        (Some(list), false) => Box::new(
+            list.iter()
+                .rev()
+                .enumerate()
+                .map(move |(index, x)| (length - 1 - index, x)),
+        ),
+    };
+    let limit = match maxlen {
+        0 => usize::MAX,
+        x => x,
+    };
+    let mut to_skip = rank.unsigned_abs() - 1;
+    let mut found = Vec::new();
+    for (index, candidate) in elements.take(limit) {
+        if *candidate != element {
+            continue;
+        }
+        if to_skip > 0 {
+            to_skip -= 1;
+            continue;
+        }
+        found.push(index);
+        if count.is_none_or(|x| x != 0 && found.len() == x) {
+            break;
+        }
+    }
+    match count {
+        None => Ok(found
+            .first()
+            .map_or(RespType::Null, |x| RespType::Integer(*x as i64))),
+        Some(_) => Ok(RespType::Array(
+            found
+                .into_iter()
+                .map(|x| RespType::Integer(x as i64))
+                .collect(),
+        )),
+    }
+}
+
+fn move_element(
+    source: Bytes,
+    destination: Bytes,
+    from: ListEnd,
+    to: ListEnd,
+    db: &mut Keyspace,
+    expiry: &mut HashMap<Bytes, SystemTime>,
+) -> Result<RespType, CommandError> {
+    if db.get_list(&source)?.is_none() {
+        return Ok(RespType::Null);
+    }
+    // Checked before popping so a WRONGTYPE destination leaves the source untouched
+    db.get_list(&destination)?;
+    let element = match db.get_list_mut(&source)?.and_then(|x| pop_from(x, from)) {
+        Some(x) => x,
+        // An empty list shouldn't be in the keyspace in the first place, it's treated as missing
+        None => {
+            remove_if_empty(&source, db, expiry);
+            return Ok(RespType::Null);
+        }
+    };
+    push_to(db.get_or_insert_list(&destination)?, to, element.clone());
+    // Only once pushed, a single element moved within the same list keeps it alive
+    remove_if_empty(&source, db, expiry);
+    Ok(RespType::BulkString(Some(element)))
+}
+
+fn push_to(list: &mut QuickList, end: ListEnd, element: Bytes) {
+    match end {
+        ListEnd::Left => list.push_front(element),
+        ListEnd::Right => list.push_back(element),
+    }
+}
+
+fn pop_from(list: &mut QuickList, end: ListEnd) -> Option<Bytes> {
+    match end {
+        ListEnd::Left => list.pop_front(),
+        ListEnd::Right => list.pop_back(),
+    }
+}
+
+// Like Redis, a list is deleted along with its expiry as soon as its last element is gone
+fn remove_if_empty(key: &Bytes, db: &mut Keyspace, expiry: &mut HashMap<Bytes, SystemTime>) {
+    if let Ok(Some(list)) = db.get_list(key) {
+        if list.is_empty() {
+            db.remove(key);
+            expiry.remove(key);
+        }
+    }
+}
+
+// Negative indexes count from the tail, None if the index falls outside the list
+fn normalize_index(index: i64, length: usize) -> Option<usize> {
+    let index = match index < 0 {
+        true => index + length as i64,
+        false => index,
+    };
+    (0..length as i64)
+        .contains(&index)
+        .then_some(index as usize)
+}
+
+// Inclusive bounds clamped to the list, None if no element falls within them
+fn normalize_range(start: i64, stop: i64, length: usize) -> Option<(usize, usize)> {
+    let length = length as i64;
+    let start = match start < 0 {
+        true => (start + length).max(0),
+        false => start,
+    };
+    let stop = match stop < 0 {
+        true => stop + length,
+        false => stop.min(length - 1),
+    };
+    (start <= stop && start < length).then_some((start as usize, stop as usize))
+}
@@ -0,0 +1,160 @@
+use super::aof::{self, Aof};
+use super::keyspace::Keyspace;
+
+use crate::config::Config;
+use crate::rdb::rdb_writer::RdbWriter;
+use crate::resp::{resp_serializer::serialize_resp_data, RespType};
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, RwLock};
+use tokio::task;
+
+pub struct SaveState {
+    pub last_save: SystemTime,
+    pub bgsave_in_progress: bool,
+}
+
+impl Default for SaveState {
+    fn default() -> Self {
+        SaveState {
+            last_save: SystemTime::now(),
+            bgsave_in_progress: false,
+        }
+    }
+}
+
+pub async fn handle_save(
+    output: &mut Vec<u8>,
+    db: Arc<Mutex<Keyspace>>,
+    expiry: Arc<RwLock<HashMap<Bytes, SystemTime>>>,
+    config: Arc<Config>,
+    save_state: Arc<Mutex<SaveState>>,
+) {
+    let response = if save_state.lock().await.bgsave_in_progress {
+        RespType::Error(String::from("ERR Background save already in progress"))
+    } else {
+        // SAVE intentionally holds the keyspace for the whole write, like Redis
+        let db = db.lock().await;
+        let expiry = expiry.read().await;
+        let rdb = RdbWriter::new().db_to_rdb(&db, &expiry);
+        match write_rdb_file(&config.rdb_path(), &rdb).await {
+            Ok(()) => {
+                save_state.lock().await.last_save = SystemTime::now();
+                RespType::SimpleString(String::from("OK"))
+            }
+            Err(e) => RespType::Error(format!("ERR Failed to save RDB file: {}", e)),
+        }
+    };
+    let response = serialize_resp_data(response);
+    output.extend_from_slice(&response);
+}
+
+pub async fn handle_bgsave(
+    output: &mut Vec<u8>,
+    db: Arc<Mutex<Keyspace>>,
+    expiry: Arc<RwLock<HashMap<Bytes, SystemTime>>>,
+    config: Arc<Config>,
+    save_state: Arc<Mutex<SaveState>>,
+) {
+    let response = {
+        let mut state = save_state.lock().await;
+        if state.bgsave_in_progress {
+            RespType::Error(String::from("ERR Background save already in progress"))
+        } else {
+            state.bgsave_in_progress = true;
+            // Only the copy happens under the locks, serializing and writing happen in the background
+            let db_snapshot = db.lock().await.clone();
+            let expiry_snapshot = expiry.read().await.clone();
+            let save_state = Arc::clone(&save_state);
+            task::spawn(async move {
+                let rdb = RdbWriter::new().db_to_rdb(&db_snapshot, &expiry_snapshot);
+                let result = write_rdb_file(&config.rdb_path(), &rdb).await;
+                let mut state = save_state.lock().await;
+                state.bgsave_in_progress = false;
+                match result {
+                    Ok(()) => state.last_save = SystemTime::now(),
+                    Err(e) => println!("Background save failed: {}", e),
+                }
+            });
+            RespType::SimpleString(String::from("Background saving started"))
+        }
+    };
+    let response = serialize_resp_data(response);
+    output.extend_from_slice(&response);
+}
+
+pub async fn handle_lastsave(output: &mut Vec<u8>, save_state: Arc<Mutex<SaveState>>) {
+    let last_save = save_state.lock().await.last_save;
+    let seconds = last_save
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0);
+    let response = serialize_resp_data(RespType::Integer(seconds as i64));
+    output.extend_from_slice(&response);
+}
+
+pub async fn handle_bgrewriteaof(
+    output: &mut Vec<u8>,
+    db: Arc<Mutex<Keyspace>>,
+    expiry: Arc<RwLock<HashMap<Bytes, SystemTime>>>,
+    config: Arc<Config>,
+    aof: Option<Arc<Aof>>,
+    write_lock: Arc<Mutex<()>>,
+) {
+    let response = match aof {
+        None => RespType::Error(String::from("ERR Append only file is not enabled")),
+        Some(aof) => {
+            // Holding the write lock makes the snapshot and the start of the rewrite buffer
+            // happen at the same point in the write stream
+            let _write_guard = write_lock.lock().await;
+            match aof.start_rewrite().await {
+                None => RespType::Error(String::from(
+                    "ERR Background append only file rewriting already in progress",
+                )),
+                Some(id) => {
+                    let db_snapshot = db.lock().await.clone();
+                    let expiry_snapshot = expiry.read().await.clone();
+                    spawn_aof_rewrite(aof, id, db_snapshot, expiry_snapshot, &config);
+                    RespType::SimpleString(String::from(
+                        "Background append only file rewriting started",
+                    ))
+                }
+            }
+        }
+    };
+    let response = serialize_resp_data(response);
+    output.extend_from_slice(&response);
+}
+
+// Writes out the rewrite started with the id from a snapshot taken under the same write lock
+pub fn spawn_aof_rewrite(
+    aof: Arc<Aof>,
+    id: u64,
+    db_snapshot: Keyspace,
+    expiry_snapshot: HashMap<Bytes, SystemTime>,
+    config: &Config,
+) {
+    let use_rdb_preamble = config.aof_use_rdb_preamble;
+    task::spawn(async move {
+        let base = aof::rewrite_base(&db_snapshot, &expiry_snapshot, use_rdb_preamble);
+        match aof.finish_rewrite(base, id).await {
+            Ok(()) => println!("Background AOF rewrite finished successfully"),
+            Err(e) => println!("Background AOF rewrite failed: {}", e),
+        }
+    });
+}
+
+// Writes to a temporary file first so a crash mid-write never leaves a truncated snapshot behind
+async fn write_rdb_file(path: &Path, rdb: &[u8]) -> std::io::Result<()> {
+    let temp_path = path.with_extension(format!("tmp-{}", std::process::id()));
+    let mut file = fs::File::create(&temp_path).await?;
+    file.write_all(rdb).await?;
+    file.sync_all().await?;
+    fs::rename(&temp_path, path).await
+}
@@ -1,218 +1,478 @@
-use super::commands::Command;
-use super::RedisState;
+use super::commands::{Command, CommandError, SetExpiry};
+use super::keyspace::{Keyspace, Value};
+use super::replication::ReplicationState;
+use super::synchronize::propagate_to_replicas;
+use super::{RedisState, ReplicaConnections};
 
 use crate::config::Config;
+use crate::rdb::rdb_writer::REDIS_VERSION;
 use crate::resp::{
-    resp_deserializer::RespParser,
-    resp_serializer::{create_null_string, serialize_command, serialize_resp_data},
-    RespType,
+    resp_serializer::{serialize_command, serialize_for_protocol, serialize_resp_data},
+    Protocol, RespType,
 };
 
+use bytes::Bytes;
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::SystemTime;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-use tokio::sync::{Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex, Notify, RwLock};
 use tokio::time::{self, Duration};
 
-pub async fn handle_echo(message: String, stream: Arc<RwLock<TcpStream>>, role: RedisState) {
-    let response = serialize_resp_data(RespType::BulkString(Some(String::from(format!(
-        "{}",
-        message
-    )))));
-    if role == RedisState::Master {
-        let mut stream = stream.write().await;
-        let _ = stream.write_all(response.as_bytes()).await;
+// Commands arriving over the master link are applied without a reply, the master isn't
+// expecting one
+pub async fn handle_echo(message: Bytes, output: &mut Vec<u8>, from_master: bool) {
+    let response = serialize_resp_data(RespType::BulkString(Some(message)));
+    if !from_master {
+        output.extend_from_slice(&response);
     }
 }
 
-pub async fn handle_ping(stream: Arc<RwLock<TcpStream>>, role: RedisState) {
+pub async fn handle_ping(output: &mut Vec<u8>, from_master: bool) {
     let response = serialize_resp_data(RespType::SimpleString(String::from("PONG")));
-    if role == RedisState::Master {
-        let mut stream = stream.write().await;
-        let _ = stream.write_all(response.as_bytes()).await;
+    if !from_master {
+        output.extend_from_slice(&response);
     }
 }
 
 pub async fn handle_set(
-    key: String,
-    value: String,
-    lifespan: Option<u64>,
-    stream: Arc<RwLock<TcpStream>>,
-    db: Arc<Mutex<HashMap<String, String>>>,
-    expiry: Arc<RwLock<HashMap<String, SystemTime>>>,
-    role: RedisState,
+    key: Bytes,
+    value: Bytes,
+    lifespan: Option<SetExpiry>,
+    output: &mut Vec<u8>,
+    db: Arc<Mutex<Keyspace>>,
+    expiry: Arc<RwLock<HashMap<Bytes, SystemTime>>>,
+    from_master: bool,
 ) {
     {
         let mut db = db.lock().await;
-        db.insert(key.clone(), value);
-    }
-    if let Some(delay_millis) = lifespan {
-        let mut expiry = expiry.write().await;
-        let lifespan = Duration::from_millis(delay_millis);
-        let now = SystemTime::now();
-        let future_time = now + lifespan;
-        expiry.insert(key.clone(), future_time);
-    } else {
         let mut expiry = expiry.write().await;
-        expiry.remove(&key);
+        apply_set(key, value, lifespan, &mut db, &mut expiry);
     }
     let response = serialize_resp_data(RespType::SimpleString(String::from("OK")));
-    if role == RedisState::Master {
-        let mut stream = stream.write().await;
-        let _ = stream.write_all(response.as_bytes()).await;
+    if !from_master {
+        output.extend_from_slice(&response);
+    }
+}
+
+// Applies a SET to the keyspace without replying, shared by live traffic and AOF replay
+pub fn apply_set(
+    key: Bytes,
+    value: Bytes,
+    lifespan: Option<SetExpiry>,
+    db: &mut Keyspace,
+    expiry: &mut HashMap<Bytes, SystemTime>,
+) {
+    match lifespan {
+        Some(SetExpiry::After(millis)) => {
+            expiry.insert(
+                key.clone(),
+                SystemTime::now() + Duration::from_millis(millis),
+            );
+        }
+        // A deadline already in the past is left to the expiry logic, which deletes the key and
+        // propagates the DEL
+        Some(SetExpiry::At(millis)) => {
+            expiry.insert(key.clone(), UNIX_EPOCH + Duration::from_millis(millis));
+        }
+        None => {
+            expiry.remove(&key);
+        }
     }
+    db.insert(key, Value::String(value));
+}
+
+pub async fn handle_del(
+    keys: Vec<Bytes>,
+    output: &mut Vec<u8>,
+    db: Arc<Mutex<Keyspace>>,
+    expiry: Arc<RwLock<HashMap<Bytes, SystemTime>>>,
+    from_master: bool,
+) {
+    let deleted = {
+        let mut db = db.lock().await;
+        let mut expiry = expiry.write().await;
+        apply_del(&keys, &mut db, &mut expiry)
+    };
+    let response = serialize_resp_data(RespType::Integer(deleted as i64));
+    if !from_master {
+        output.extend_from_slice(&response);
+    }
+}
+
+// Removes the keys along with their expiries, returning how many existed
+pub fn apply_del(
+    keys: &[Bytes],
+    db: &mut Keyspace,
+    expiry: &mut HashMap<Bytes, SystemTime>,
+) -> usize {
+    let mut deleted = 0;
+    for key in keys {
+        expiry.remove(key);
+        if db.remove(key).is_some() {
+            deleted += 1;
+        }
+    }
+    deleted
 }
 
 pub async fn handle_get(
-    key: String,
-    stream: Arc<RwLock<TcpStream>>,
-    db: Arc<Mutex<HashMap<String, String>>>,
-    expiry: Arc<RwLock<HashMap<String, SystemTime>>>,
+    key: Bytes,
+    output: &mut Vec<u8>,
+    db: Arc<Mutex<Keyspace>>,
+    expiry: Arc<RwLock<HashMap<Bytes, SystemTime>>>,
+    protocol: Protocol,
 ) {
     let db = db.lock().await;
     let expiry = expiry.read().await;
-    let mut response = create_null_string();
-    match db.get(&key) {
-        Some(value) => {
-            response = serialize_resp_data(RespType::BulkString(Some(String::from(value))));
-            if let Some(expiration) = expiry.get(&key) {
-                if SystemTime::now() > *expiration {
-                    response = create_null_string();
-                }
-            }
+    // Replicas keep expired keys until the master's DEL arrives, but don't serve them
+    let response = match db.get_string(&key) {
+        _ if is_logically_expired(&key, &expiry) => RespType::Null,
+        Ok(Some(value)) => RespType::BulkString(Some(value.clone())),
+        Ok(None) => RespType::Null,
+        Err(e) => RespType::Error(e.to_string()),
+    };
+    let response = serialize_for_protocol(response, protocol);
+    output.extend_from_slice(&response);
+}
+
+pub async fn handle_type(
+    key: Bytes,
+    output: &mut Vec<u8>,
+    db: Arc<Mutex<Keyspace>>,
+    expiry: Arc<RwLock<HashMap<Bytes, SystemTime>>>,
+) {
+    let db = db.lock().await;
+    let expiry = expiry.read().await;
+    let type_name = match is_logically_expired(&key, &expiry) {
+        true => "none",
+        false => db.type_of(&key),
+    };
+    let response = serialize_resp_data(RespType::SimpleString(String::from(type_name)));
+    output.extend_from_slice(&response);
+}
+
+pub fn is_logically_expired(key: &Bytes, expiry: &HashMap<Bytes, SystemTime>) -> bool {
+    expiry
+        .get(key)
+        .is_some_and(|expiration| SystemTime::now() > *expiration)
+}
+
+// Sent as the usual field:value lines over RESP2 and as a map of fields over RESP3
+pub async fn handle_info(
+    _arg: String,
+    output: &mut Vec<u8>,
+    replica_connections: ReplicaConnections,
+    replication: Arc<Mutex<ReplicationState>>,
+    protocol: Protocol,
+) {
+    let fields = {
+        let replication = replication.lock().await;
+        let mut fields = vec![(String::from("role"), replication.role.to_string())];
+        if replication.role == RedisState::Replica {
+            let link_status = if replication.master_link_up {
+                "up"
+            } else {
+                "down"
+            };
+            let last_io = replication
+                .master_last_io
+                .map(|x| x.elapsed().as_secs() as i64)
+                .unwrap_or(-1);
+            fields.extend([
+                (
+                    String::from("master_host"),
+                    replication.master_host.clone().unwrap_or_default(),
+                ),
+                (
+                    String::from("master_port"),
+                    replication.master_port.clone().unwrap_or_default(),
+                ),
+                (String::from("master_link_status"), link_status.to_string()),
+                (
+                    String::from("master_last_io_seconds_ago"),
+                    last_io.to_string(),
+                ),
+            ]);
         }
-        None => (),
-    }
-    let mut stream = stream.write().await;
-    let _ = stream.write_all(response.as_bytes()).await;
-}
-
-pub async fn handle_info(_arg: String, config: Arc<Config>, stream: Arc<RwLock<TcpStream>>) {
-    let response = match config.role {
-        RedisState::Master => serialize_resp_data(RespType::BulkString(Some(format!(
-            "role:{}\nmaster_replid:{}\nmaster_repl_offset:{}\n",
-            config.role,
-            config.master_replid.as_ref().unwrap(),
-            config.master_repl_offset.as_ref().unwrap()
-        )))),
-        RedisState::Replica => {
-            serialize_resp_data(RespType::BulkString(format!("role:{}", config.role).into()))
+        let connections = replica_connections.read().await;
+        fields.push((
+            String::from("connected_slaves"),
+            connections.len().to_string(),
+        ));
+        for (index, replica) in connections.values().enumerate() {
+            fields.push((
+                format!("slave{}", index),
+                format!(
+                    "ip={},port={},state=online,offset={},lag={}",
+                    replica.ip,
+                    replica.listening_port,
+                    replica.ack_offset,
+                    replica.last_ack.elapsed().as_secs()
+                ),
+            ));
+        }
+        let second_repl_offset = replication
+            .second_replid_offset
+            .map(|x| x as i64)
+            .unwrap_or(-1);
+        fields.extend([
+            (String::from("master_replid"), replication.replid.clone()),
+            (String::from("master_replid2"), replication.replid2.clone()),
+            (
+                String::from("master_repl_offset"),
+                replication.offset.to_string(),
+            ),
+            (
+                String::from("second_repl_offset"),
+                second_repl_offset.to_string(),
+            ),
+            (String::from("repl_backlog_active"), String::from("1")),
+            (
+                String::from("repl_backlog_size"),
+                replication.backlog.capacity().to_string(),
+            ),
+            (
+                String::from("repl_backlog_first_byte_offset"),
+                (replication.backlog_start() + 1).to_string(),
+            ),
+            (
+                String::from("repl_backlog_histlen"),
+                replication.backlog.histlen().to_string(),
+            ),
+        ]);
+        fields
+    };
+    let response = match protocol {
+        Protocol::Resp2 => {
+            let info: String = fields
+                .into_iter()
+                .map(|(field, value)| format!("{}:{}\n", field, value))
+                .collect();
+            RespType::BulkString(Some(Bytes::from(info)))
         }
+        Protocol::Resp3 => RespType::Map(
+            fields
+                .into_iter()
+                .map(|(field, value)| {
+                    (
+                        RespType::BulkString(Some(Bytes::from(field))),
+                        RespType::BulkString(Some(Bytes::from(value))),
+                    )
+                })
+                .collect(),
+        ),
     };
-    let mut stream = stream.write().await;
-    let _ = stream.write_all(response.as_bytes()).await;
+    let response = serialize_for_protocol(response, protocol);
+    output.extend_from_slice(&response);
 }
 
 pub async fn handle_config_get(
-    stream: Arc<RwLock<TcpStream>>,
+    output: &mut Vec<u8>,
     config: Arc<Config>,
     path_type: String,
+    protocol: Protocol,
 ) {
-    let path: String = match path_type.to_lowercase().as_str() {
-        "dir" => config
-            .rdb_dir
-            .as_ref()
-            .and_then(|p| p.to_str())
-            .expect("Failed to convert path to string")
-            .to_string(),
-        "dbfilename" => config
-            .rdb_filename
-            .as_ref()
-            .and_then(|p| p.to_str())
-            .expect("Failed to convert path to string")
-            .to_string(),
-        other @ _ => panic!("Unsupported argument for CONFIG GET: {}", other),
+    let path = match path_type.to_lowercase().as_str() {
+        "dir" => config.rdb_dir.as_ref(),
+        "dbfilename" => config.rdb_filename.as_ref(),
+        _ => None,
     };
-    let response = serialize_resp_data(RespType::Array(vec![
-        RespType::BulkString(Some(path_type)),
-        RespType::BulkString(Some(path)),
-    ]));
-    let mut stream = stream.write().await;
-    let _ = stream.write_all(response.as_bytes()).await;
+    // Parameters that aren't supported or set are left out, like Redis does for unknown ones.
+    // A flat array of parameter and value over RESP2
+    let response = serialize_for_protocol(
+        RespType::Map(
+            path.into_iter()
+                .map(|x| {
+                    (
+                        RespType::BulkString(Some(Bytes::from(path_type.clone()))),
+                        RespType::BulkString(Some(Bytes::from(x.to_string_lossy().to_string()))),
+                    )
+                })
+                .collect(),
+        ),
+        protocol,
+    );
+    output.extend_from_slice(&response);
 }
 
-pub async fn handle_keys(
-    stream: Arc<RwLock<TcpStream>>,
-    db: Arc<Mutex<HashMap<String, String>>>,
-    _arg: String,
-) {
+// Switches the connection to the requested protocol and describes the server in it, returning
+// the protocol the connection is on afterwards. There are no users or passwords to check, so
+// AUTH only accepts the default user
+pub async fn handle_hello(
+    protocol_version: Option<i64>,
+    auth: Option<(String, String)>,
+    client_name: Option<String>,
+    protocol: Protocol,
+    client_id: u64,
+    output: &mut Vec<u8>,
+    replication: Arc<Mutex<ReplicationState>>,
+) -> Protocol {
+    let new_protocol = match protocol_version {
+        None => Some(protocol),
+        Some(2) => Some(Protocol::Resp2),
+        Some(3) => Some(Protocol::Resp3),
+        Some(_) => None,
+    };
+    let error = match (new_protocol, &auth) {
+        (None, _) => Some("NOPROTO unsupported protocol version"),
+        (_, Some((username, _))) if username != "default" => {
+            Some("WRONGPASS invalid username-password pair or user is disabled.")
+        }
+        _ => None,
+    };
+    let (response, protocol) = match (error, new_protocol) {
+        (None, Some(new_protocol)) => {
+            if let Some(name) = client_name {
+                println!("Client is now named {}", name);
+            }
+            let role = match replication.lock().await.role {
+                RedisState::Master => "master",
+                RedisState::Replica => "replica",
+            };
+            let proto = match new_protocol {
+                Protocol::Resp2 => 2,
+                Protocol::Resp3 => 3,
+            };
+            let field = |x: &str| RespType::BulkString(Some(Bytes::from(x.to_string())));
+            let response = RespType::Map(vec![
+                (field("server"), field("redis")),
+                (field("version"), field(REDIS_VERSION)),
+                (field("proto"), RespType::Integer(proto)),
+                (field("id"), RespType::Integer(client_id as i64)),
+                (field("mode"), field("standalone")),
+                (field("role"), field(role)),
+                (field("modules"), RespType::Array(vec![])),
+            ]);
+            (response, new_protocol)
+        }
+        (error, _) => (
+            RespType::Error(String::from(error.unwrap_or_default())),
+            protocol,
+        ),
+    };
+    let response = serialize_for_protocol(response, protocol);
+    output.extend_from_slice(&response);
+    protocol
+}
+
+pub async fn handle_keys(output: &mut Vec<u8>, db: Arc<Mutex<Keyspace>>, _arg: String) {
     // NOTE: Assuming arg is always *
     let db = db.lock().await;
-    let keys: Vec<String> = db.keys().cloned().collect();
+    let keys: Vec<Bytes> = db.keys().cloned().collect();
     let resp_keys: Vec<RespType> = keys
         .into_iter()
         .map(|key| RespType::BulkString(Some(key)))
         .collect();
     let response = serialize_resp_data(RespType::Array(resp_keys));
-    let mut stream = stream.write().await;
-    let _ = stream.write_all(response.as_bytes()).await;
+    output.extend_from_slice(&response);
 }
 
+// Replies with the number of replicas that acknowledged the client's last write, as soon as
+// enough of them have or once the timeout runs out. A timeout of 0 waits indefinitely
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_wait(
-    replica_connections: Arc<RwLock<Option<HashMap<i32, Arc<RwLock<TcpStream>>>>>>,
-    stream: Arc<RwLock<TcpStream>>,
+    replica_connections: ReplicaConnections,
+    output: &mut Vec<u8>,
     timeout: i32,
-    _replicas_to_wait_for: i32,
-    write_bytes_processed: usize,
+    replicas_to_wait_for: i32,
+    last_write_offset: usize,
     write_commands_to_process: usize,
+    replication: Arc<Mutex<ReplicationState>>,
+    write_lock: Arc<Mutex<()>>,
+    replica_acks: Arc<Notify>,
 ) {
-    let get_ack_command = serialize_command(&Command::ReplConf(
-        String::from("GETACK"),
-        Some(String::from("*")),
-    ));
-    let replica_connections = replica_connections.read().await;
-    let mut up_to_date_replicas: usize = 0;
-    if write_commands_to_process == 0 {
-        up_to_date_replicas = replica_connections.as_ref().unwrap().values().len();
-        let response = serialize_resp_data(RespType::Integer(up_to_date_replicas as i64));
-        let mut stream = stream.write().await;
-        let _ = stream.write_all(response.as_bytes()).await;
-        return;
+    if write_commands_to_process > 0 {
+        // GETACK goes through the replication stream like any other command so replica
+        // offsets stay in line with the master's. The ACKs are picked up by each replica's
+        // own connection
+        let get_ack_command = serialize_command(&Command::ReplConf(
+            String::from("GETACK"),
+            Some(String::from("*")),
+        ));
+        let _write_guard = write_lock.lock().await;
+        propagate_to_replicas(
+            &get_ack_command,
+            Arc::clone(&replica_connections),
+            Arc::clone(&replication),
+        )
+        .await;
     }
 
-    let timeout = Duration::from_millis(timeout as u64);
-    let connections = replica_connections
-        .as_ref()
-        .expect("Master didn't have replica_connections while processing WAIT");
-    let mut replica_fds: Vec<i32> = connections.keys().copied().collect();
-    replica_fds.sort();
-
-    for fd in replica_fds {
-        let replica_stream = connections.get(&fd).unwrap();
-        let mut parser = RespParser::new(String::from(""), Arc::clone(&replica_stream));
-        {
-            let mut replica_stream = replica_stream.write().await;
-            let _ = replica_stream.write_all(get_ack_command.as_bytes()).await;
+    let replicas_to_wait_for = replicas_to_wait_for.max(0) as usize;
+    let deadline =
+        (timeout > 0).then(|| time::Instant::now() + Duration::from_millis(timeout as u64));
+    let up_to_date_replicas = loop {
+        // Registered before counting so an ACK arriving in between still wakes us up
+        let acked = replica_acks.notified();
+        let up_to_date_replicas =
+            count_up_to_date_replicas(&replica_connections, last_write_offset).await;
+        if up_to_date_replicas >= replicas_to_wait_for {
+            break up_to_date_replicas;
         }
-
-        // If we don't recieve a response within timeout, continue
-        let response = match time::timeout(timeout, parser.parse_command()).await {
-            Ok(Some((command, _bytes_read))) => command,
-            Ok(None) => panic!("Didn't recieve response to GETACK"),
-            Err(_) => continue,
-        };
-
-        match response {
-            Command::ReplConf(arg1, x) => {
-                if arg1.as_str() == "ACK" {
-                    if let Some(byte_processed_by_repliac) = x {
-                        if byte_processed_by_repliac.parse::<usize>().unwrap()
-                            >= write_bytes_processed
-                        {
-                            up_to_date_replicas += 1;
-                        }
-                    }
-                } else {
-                    panic!("Expected REPLCONF Response to GETACK to be ACK");
+        match deadline {
+            Some(deadline) => {
+                if time::timeout_at(deadline, acked).await.is_err() {
+                    break count_up_to_date_replicas(&replica_connections, last_write_offset).await;
                 }
             }
-            _ => panic!("Expected REPLCONF response to GETACK"),
+            None => acked.await,
         }
-    }
+    };
 
     let response = serialize_resp_data(RespType::Integer(up_to_date_replicas as i64));
-    let mut stream = stream.write().await;
-    let _ = stream.write_all(response.as_bytes()).await;
+    output.extend_from_slice(&response);
+}
+
+async fn count_up_to_date_replicas(
+    replica_connections: &ReplicaConnections,
+    offset: usize,
+) -> usize {
+    replica_connections
+        .read()
+        .await
+        .values()
+        .filter(|x| x.ack_offset >= offset)
+        .count()
+}
+
+// Whether enough replicas have acknowledged recently for the master to accept a write, always
+// true unless min-replicas-to-write is set
+pub async fn has_enough_good_replicas(
+    config: &Config,
+    replica_connections: &ReplicaConnections,
+) -> bool {
+    if config.min_replicas_to_write == 0 {
+        return true;
+    }
+    let good_replicas = replica_connections
+        .read()
+        .await
+        .values()
+        .filter(|x| x.last_ack.elapsed().as_secs() <= config.min_replicas_max_lag)
+        .count();
+    good_replicas >= config.min_replicas_to_write
+}
+
+// The master isn't answered, a command it sent that can't be run is only logged
+pub async fn handle_command_error(error: CommandError, output: &mut Vec<u8>, is_master_link: bool) {
+    if is_master_link {
+        println!("Failed to run command from master: {}", error);
+        return;
+    }
+    let response = serialize_resp_data(RespType::Error(error.to_string()));
+    output.extend_from_slice(&response);
+}
+
+pub async fn handle_noreplicas(output: &mut Vec<u8>) {
+    let response = serialize_resp_data(RespType::Error(String::from(
+        "NOREPLICAS Not enough good replicas to write.",
+    )));
+    output.extend_from_slice(&response);
+}
+
+pub async fn handle_readonly(output: &mut Vec<u8>) {
+    let response = serialize_resp_data(RespType::Error(String::from(
+        "READONLY You can't write against a read only replica.",
+    )));
+    output.extend_from_slice(&response);
 }
@@ -0,0 +1,359 @@
+use bytes::Bytes;
+use std::collections::VecDeque;
+
+// Nodes are capped like Redis's default list-max-listpack-size of -2, 8kb or a number of entries
+const NODE_MAX_BYTES: usize = 8 * 1024;
+const NODE_MAX_ENTRIES: usize = 128;
+
+// A list kept as a chain of small nodes, so pushes and pops at either end stay cheap while
+// inserting or removing in the middle only shifts the elements of one node. Nodes are never empty
+#[derive(Clone, Debug, Default)]
+pub struct QuickList {
+    nodes: VecDeque<Node>,
+    len: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Node {
+    entries: VecDeque<Bytes>,
+    bytes: usize,
+}
+
+impl Node {
+    fn has_room_for(&self, element: &Bytes) -> bool {
+        self.entries.len() < NODE_MAX_ENTRIES && self.bytes + element.len() <= NODE_MAX_BYTES
+    }
+
+    fn is_oversized(&self) -> bool {
+        self.entries.len() > 1
+            && (self.entries.len() > NODE_MAX_ENTRIES || self.bytes > NODE_MAX_BYTES)
+    }
+}
+
+impl QuickList {
+    pub fn new() -> QuickList {
+        QuickList::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push_front(&mut self, element: Bytes) {
+        if !self.nodes.front().is_some_and(|x| x.has_room_for(&element)) {
+            self.nodes.push_front(Node::default());
+        }
+        let node = &mut self.nodes[0];
+        node.bytes += element.len();
+        node.entries.push_front(element);
+        self.len += 1;
+    }
+
+    pub fn push_back(&mut self, element: Bytes) {
+        if !self.nodes.back().is_some_and(|x| x.has_room_for(&element)) {
+            self.nodes.push_back(Node::default());
+        }
+        let last = self.nodes.len() - 1;
+        let node = &mut self.nodes[last];
+        node.bytes += element.len();
+        node.entries.push_back(element);
+        self.len += 1;
+    }
+
+    pub fn pop_front(&mut self) -> Option<Bytes> {
+        match self.len {
+            0 => None,
+            _ => self.remove_at(0, 0),
+        }
+    }
+
+    pub fn pop_back(&mut self) -> Option<Bytes> {
+        let last = self.nodes.len().checked_sub(1)?;
+        let position = self.nodes[last].entries.len() - 1;
+        self.remove_at(last, position)
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Bytes> {
+        let (node, position) = self.locate(index)?;
+        self.nodes[node].entries.get(position)
+    }
+
+    // Returns false if the index is out of range
+    pub fn set(&mut self, index: usize, element: Bytes) -> bool {
+        let (node, position) = match self.locate(index) {
+            Some(x) => x,
+            None => return false,
+        };
+        let entry = &mut self.nodes[node];
+        entry.bytes = entry.bytes - entry.entries[position].len() + element.len();
+        entry.entries[position] = element;
+        self.split_if_oversized(node);
+        true
+    }
+
+    // Inserts so the element ends up at the index, which may be the length to append
+    pub fn insert(&mut self, index: usize, element: Bytes) {
+        if index >= self.len {
+            self.push_back(element);
+            return;
+        }
+        let (node, position) = self
+            .locate(index)
+            .expect("Index was checked to be in range");
+        let entry = &mut self.nodes[node];
+        entry.bytes += element.len();
+        entry.entries.insert(position, element);
+        self.len += 1;
+        self.split_if_oversized(node);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Bytes> {
+        let (node, position) = self.locate(index)?;
+        self.remove_at(node, position)
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Bytes> {
+        self.nodes.iter().flat_map(|x| x.entries.iter())
+    }
+
+    // The elements grouped the way they are stored, the RDB keeps one listpack per node
+    pub fn nodes(&self) -> impl Iterator<Item = &VecDeque<Bytes>> {
+        self.nodes.iter().map(|x| &x.entries)
+    }
+
+    // Finds the node holding the element at the index and its position in there, walking from
+    // whichever end of the list is closer
+    fn locate(&self, index: usize) -> Option<(usize, usize)> {
+        if index >= self.len {
+            return None;
+        }
+        if index < self.len / 2 {
+            let mut remaining = index;
+            for (node, entry) in self.nodes.iter().enumerate() {
+                if remaining < entry.entries.len() {
+                    return Some((node, remaining));
+                }
+                remaining -= entry.entries.len();
+            }
+        } else {
+            let mut remaining = self.len - 1 - index;
+            for (node, entry) in self.nodes.iter().enumerate().rev() {
+                if remaining < entry.entries.len() {
+                    return Some((node, entry.entries.len() - 1 - remaining));
+                }
+                remaining -= entry.entries.len();
+            }
+        }
+        None
+    }
+
+    fn remove_at(&mut self, node: usize, position: usize) -> Option<Bytes> {
+        let entry = &mut self.nodes[node];
+        let element = entry.entries.remove(position)?;
+        entry.bytes -= element.len();
+        match entry.entries.is_empty() {
+            true => {
+                self.nodes.remove(node);
+            }
+            false => self.merge_with_neighbour(node),
+        }
+        self.len -= 1;
+        Some(element)
+    }
+
+    // Folds the node into a neighbour when the two fit in one, so removals don't leave behind a
+    // long chain of nearly empty nodes
+    fn merge_with_neighbour(&mut self, node: usize) {
+        if node + 1 < self.nodes.len() && self.fit_in_one(node, node + 1) {
+            self.merge_next(node);
+        } else if node > 0 && self.fit_in_one(node - 1, node) {
+            self.merge_next(node - 1);
+        }
+    }
+
+    fn fit_in_one(&self, first: usize, second: usize) -> bool {
+        let (first, second) = (&self.nodes[first], &self.nodes[second]);
+        first.entries.len() + second.entries.len() <= NODE_MAX_ENTRIES
+            && first.bytes + second.bytes <= NODE_MAX_BYTES
+    }
+
+    // Appends the node after this one to it
+    fn merge_next(&mut self, node: usize) {
+        let next = self
+            .nodes
+            .remove(node + 1)
+            .expect("Only called with a node after this one");
+        let entry = &mut self.nodes[node];
+        entry.bytes += next.bytes;
+        entry.entries.extend(next.entries);
+    }
+
+    fn split_if_oversized(&mut self, node: usize) {
+        if !self.nodes[node].is_oversized() {
+            return;
+        }
+        let entry = &mut self.nodes[node];
+        let entries = entry.entries.split_off(entry.entries.len() / 2);
+        let bytes = entries.iter().map(|x| x.len()).sum();
+        entry.bytes -= bytes;
+        self.nodes.insert(node + 1, Node { entries, bytes });
+        // A large element can leave either half over the byte limit
+        self.split_if_oversized(node + 1);
+        self.split_if_oversized(node);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(index: usize) -> Bytes {
+        Bytes::from(index.to_string())
+    }
+
+    fn list_of(length: usize) -> QuickList {
+        let mut list = QuickList::new();
+        for index in 0..length {
+            list.push_back(element(index));
+        }
+        list
+    }
+
+    fn node_sizes(list: &QuickList) -> Vec<usize> {
+        list.nodes().map(|x| x.len()).collect()
+    }
+
+    // Every node is non empty, within its limits unless it holds a single element, and keeps
+    // count of its bytes
+    fn assert_consistent(list: &QuickList) {
+        assert_eq!(list.iter().count(), list.len());
+        for node in list.nodes.iter() {
+            assert!(!node.entries.is_empty());
+            assert!(!node.is_oversized());
+            assert_eq!(node.bytes, node.entries.iter().map(|x| x.len()).sum());
+        }
+    }
+
+    #[test]
+    fn pushes_fill_nodes_up_to_the_entry_limit() {
+        let list = list_of(300);
+        assert_eq!(node_sizes(&list), vec![128, 128, 44]);
+        let mut list = QuickList::new();
+        for index in 0..300 {
+            list.push_front(element(index));
+        }
+        assert_eq!(node_sizes(&list), vec![44, 128, 128]);
+        assert_consistent(&list);
+    }
+
+    #[test]
+    fn pushes_start_a_node_once_the_byte_limit_is_reached() {
+        let mut list = QuickList::new();
+        for _ in 0..5 {
+            list.push_back(Bytes::from(vec![b'a'; 3000]));
+        }
+        assert_eq!(node_sizes(&list), vec![2, 2, 1]);
+        // A single element larger than a node gets one to itself
+        list.push_back(Bytes::from(vec![b'a'; 10000]));
+        assert_eq!(node_sizes(&list), vec![2, 2, 1, 1]);
+        assert_consistent(&list);
+    }
+
+    #[test]
+    fn locates_from_both_ends() {
+        let list = list_of(300);
+        assert_eq!(list.locate(0), Some((0, 0)));
+        assert_eq!(list.locate(127), Some((0, 127)));
+        assert_eq!(list.locate(128), Some((1, 0)));
+        assert_eq!(list.locate(149), Some((1, 21)));
+        assert_eq!(list.locate(150), Some((1, 22)));
+        assert_eq!(list.locate(256), Some((2, 0)));
+        assert_eq!(list.locate(299), Some((2, 43)));
+        assert_eq!(list.locate(300), None);
+        for index in 0..300 {
+            assert_eq!(list.get(index), Some(&element(index)));
+        }
+    }
+
+    #[test]
+    fn pops_across_node_boundaries() {
+        let mut list = list_of(300);
+        for index in 0..130 {
+            assert_eq!(list.pop_front(), Some(element(index)));
+        }
+        assert_eq!(node_sizes(&list), vec![126, 44]);
+        for index in (130..300).rev() {
+            assert_eq!(list.pop_back(), Some(element(index)));
+            assert_consistent(&list);
+        }
+        assert!(list.is_empty());
+        assert_eq!(list.nodes().count(), 0);
+        assert_eq!(list.pop_front(), None);
+        assert_eq!(list.pop_back(), None);
+    }
+
+    #[test]
+    fn insert_splits_a_full_node() {
+        let mut list = list_of(128);
+        list.insert(10, Bytes::from("new"));
+        assert_eq!(node_sizes(&list), vec![64, 65]);
+        assert_eq!(list.get(10), Some(&Bytes::from("new")));
+        assert_eq!(list.get(11), Some(&element(10)));
+        assert_eq!(list.len(), 129);
+        assert_consistent(&list);
+    }
+
+    #[test]
+    fn set_splits_a_node_over_the_byte_limit() {
+        let mut list = list_of(100);
+        assert!(list.set(50, Bytes::from(vec![b'a'; NODE_MAX_BYTES])));
+        // Split until the large element ends up in a node of its own
+        assert!(list
+            .nodes()
+            .any(|x| x.len() == 1 && x[0].len() == NODE_MAX_BYTES));
+        assert_eq!(list.get(50).map(|x| x.len()), Some(NODE_MAX_BYTES));
+        assert_eq!(list.get(51), Some(&element(51)));
+        assert_eq!(list.len(), 100);
+        assert!(!list.set(100, element(0)));
+        assert_consistent(&list);
+    }
+
+    #[test]
+    fn removing_merges_nodes_that_fit_in_one() {
+        let mut list = list_of(256);
+        assert_eq!(node_sizes(&list), vec![128, 128]);
+        for _ in 0..64 {
+            list.remove(64);
+        }
+        assert_eq!(node_sizes(&list), vec![64, 128]);
+        // Shrinking the second node until both fit in one merges them
+        for _ in 0..63 {
+            list.remove(64);
+        }
+        assert_eq!(node_sizes(&list), vec![64, 65]);
+        list.remove(64);
+        assert_eq!(node_sizes(&list), vec![128]);
+        let expected: Vec<Bytes> = (0..64).chain(192..256).map(element).collect();
+        assert_eq!(list.iter().cloned().collect::<Vec<_>>(), expected);
+        assert_consistent(&list);
+    }
+
+    #[test]
+    fn removing_merges_with_the_previous_node() {
+        let mut list = list_of(300);
+        while list.len() > 200 {
+            list.remove(list.len() - 1);
+        }
+        // The last node emptied, then the middle one shrank until it fit with the tail one
+        assert_eq!(node_sizes(&list), vec![128, 72]);
+        while list.len() > 100 {
+            list.remove(0);
+        }
+        assert_eq!(node_sizes(&list), vec![100]);
+        assert_consistent(&list);
+    }
+}
@@ -1,138 +1,490 @@
+use bytes::Bytes;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio::task::{self, JoinHandle};
+use tokio::time::{self, Duration};
 
+use super::commands::Command;
+use super::connection::{split_connection, ConnectionWriter};
 use super::construct_rdb;
-use crate::resp::{resp_deserializer::RespParser, resp_serializer::serialize_resp_data, RespType};
-use crate::Redis;
+use super::keyspace::Keyspace;
+use super::persistence;
+use super::replication::{generate_replid, ReplicaLink, ReplicationState};
+use super::synchronize::disconnect_replicas;
+use crate::rdb::rdb_parser::RdbParser;
+use crate::rdb::rdb_writer::RdbWriter;
+use crate::redis::{Redis, RedisState, ReplicaConnections};
+use crate::resp::{
+    resp_deserializer::RespParser,
+    resp_serializer::{serialize_command, serialize_resp_data},
+    RespType,
+};
 
-pub async fn handle_replconf(stream: Arc<RwLock<TcpStream>>) {
-    let response = String::from("+OK\r\n");
-    let mut stream = stream.write().await;
-    let _ = stream.write_all(response.as_bytes()).await;
+// Sent across tasks, so unlike the errors returned from main it has to be Send
+pub type HandshakeError = Box<dyn std::error::Error + Send + Sync>;
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// RDB chunks a diskless transfer gets ahead of a replica's socket by
+const DISKLESS_QUEUED_CHUNKS: usize = 4;
+const KEEPALIVE_PERIOD: Duration = Duration::from_secs(1);
+
+pub async fn handle_replconf(output: &mut Vec<u8>) {
+    let response = serialize_resp_data(RespType::SimpleString(String::from("OK")));
+    output.extend_from_slice(&response);
 }
 
-pub async fn handle_replconf_getack(stream: Arc<RwLock<TcpStream>>, bytes_processed: usize) {
+pub async fn handle_replconf_getack(output: &mut Vec<u8>, bytes_processed: usize) {
     let response = RespType::Array(vec![
-        RespType::BulkString(Some(String::from("REPLCONF"))),
-        RespType::BulkString(Some(String::from("ACK"))),
-        RespType::BulkString(Some(bytes_processed.to_string())),
+        RespType::BulkString(Some(Bytes::from("REPLCONF"))),
+        RespType::BulkString(Some(Bytes::from("ACK"))),
+        RespType::BulkString(Some(Bytes::from(bytes_processed.to_string()))),
     ]);
 
     let serialized_response = serialize_resp_data(response);
 
-    let mut stream = stream.write().await;
-    let _ = stream.write_all(serialized_response.as_bytes()).await;
+    output.extend_from_slice(&serialized_response);
 }
 
+// Must be called with the write lock held so the snapshot or backlog matches the offset sent.
+// Returns false without replying when the replica needs a full resync and it is to be diskless,
+// the replica then has to be queued with schedule_diskless_sync
 pub async fn handle_psync(
-    _replication_id: String,
-    _offset: String,
-    stream: Arc<RwLock<TcpStream>>,
-    db: Arc<Mutex<HashMap<String, String>>>,
-) {
-    {
-        let mut stream = stream.write().await;
+    replication_id: String,
+    offset: String,
+    writer: &ConnectionWriter,
+    db: Arc<Mutex<Keyspace>>,
+    expiry: Arc<RwLock<HashMap<Bytes, SystemTime>>>,
+    replication: Arc<Mutex<ReplicationState>>,
+    diskless: bool,
+) -> bool {
+    let replication = replication.lock().await;
+    // The replica asks for the first byte it hasn't processed yet
+    let backlog_data = offset
+        .parse::<usize>()
+        .ok()
+        .and_then(|x| x.checked_sub(1))
+        .and_then(|x| replication.bytes_since(&replication_id, x));
 
-        let repl_id = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
-        let response =
-            serialize_resp_data(RespType::SimpleString(format!("FULLRESYNC {} 0", repl_id)));
-        let (length, binary) = construct_rdb(Arc::clone(&db));
+    match backlog_data {
+        Some(data) => {
+            println!(
+                "Partial resync accepted, sending {} bytes of backlog",
+                data.len()
+            );
+            let mut response = serialize_resp_data(RespType::SimpleString(format!(
+                "CONTINUE {}",
+                replication.replid
+            )));
+            response.extend_from_slice(&data);
+            writer.send(response);
+        }
+        None if diskless => return false,
+        None => {
+            let mut response = serialize_resp_data(RespType::SimpleString(format!(
+                "FULLRESYNC {} {}",
+                replication.replid, replication.offset
+            )));
+            let (length, binary) = {
+                let db = db.lock().await;
+                let expiry = expiry.read().await;
+                construct_rdb(&db, &expiry)
+            };
 
-        let _ = stream.write_all(response.as_bytes()).await;
-        let _ = stream.write_all(length.as_bytes()).await;
-        let _ = stream.write_all(&binary).await;
+            response.extend_from_slice(length.as_bytes());
+            response.extend_from_slice(&binary);
+            writer.send(response);
+        }
     }
+    true
 }
 
-// TODO: Add memoization for efficiency as we scale
-pub async fn is_stream_replica(
-    replica_connections: Arc<RwLock<Option<HashMap<i32, Arc<RwLock<TcpStream>>>>>>,
-    stream: Arc<RwLock<TcpStream>>,
-) -> bool {
-    use std::os::unix::io::AsRawFd;
-
-    let replica_connections = replica_connections.write().await;
-    if let Some(ref connections) = *replica_connections {
-        let stream = stream.read().await;
-        let stream_fd = stream.as_raw_fd();
-        for (replica_fd, _replica_stream) in connections.iter() {
-            if stream_fd == *replica_fd {
-                return true;
+// Queues a replica for the next diskless transfer, starting one if none is pending
+pub async fn schedule_diskless_sync(id: u64, replica: ReplicaLink, redis: Redis) {
+    let mut pending = redis.diskless_syncs.lock().await;
+    pending.push((id, replica));
+    if pending.len() == 1 {
+        task::spawn(diskless_sync(redis.clone()));
+    }
+}
+
+// Sends one snapshot to every replica that asked for a full resync during the delay. The RDB goes
+// straight into the sockets as it is serialized, framed by a random marker rather than its length
+async fn diskless_sync(redis: Redis) {
+    // The waiting replicas get a newline every second, like Redis sends them, so a delay longer
+    // than their replication timeout doesn't make them give up
+    let delay = Duration::from_secs(redis.config.repl_diskless_sync_delay);
+    let deadline = time::Instant::now() + delay;
+    while time::Instant::now() < deadline {
+        time::sleep_until(deadline.min(time::Instant::now() + KEEPALIVE_PERIOD)).await;
+        for (_, replica) in redis.diskless_syncs.lock().await.iter() {
+            replica.writer.send(Bytes::from_static(b"\n"));
+        }
+    }
+    let mark = generate_replid();
+    let mut streams = vec![];
+    let (db_snapshot, expiry_snapshot) = {
+        // No write may land between the snapshot and the replicas being registered. Whatever is
+        // propagated afterwards queues up behind the RDB
+        let _write_guard = redis.write_lock.lock().await;
+        let replicas = std::mem::take(&mut *redis.diskless_syncs.lock().await);
+        let mut header = {
+            let replication = redis.replication.lock().await;
+            serialize_resp_data(RespType::SimpleString(format!(
+                "FULLRESYNC {} {}",
+                replication.replid, replication.offset
+            )))
+        };
+        header.extend_from_slice(format!("$EOF:{}\r\n", mark).as_bytes());
+        let header = Bytes::from(header);
+        let mut replica_connections = redis.replica_connections.write().await;
+        for (id, replica) in replicas {
+            let (sender, receiver) = mpsc::channel(DISKLESS_QUEUED_CHUNKS);
+            // Sends fail for a replica that left while waiting, it asks again when it reconnects
+            if replica.writer.send(header.clone()) && replica.writer.send_stream(receiver) {
+                streams.push(sender);
+                replica_connections.insert(id, replica);
             }
         }
+        // Only the copy happens under the locks, like for BGSAVE
+        let db_snapshot = redis.database.lock().await.clone();
+        let expiry_snapshot = redis.expiry.read().await.clone();
+        (db_snapshot, expiry_snapshot)
+    };
+    println!("Starting diskless sync to {} replicas", streams.len());
+
+    // Every chunk goes to all of the replicas, so the slowest one sets the pace
+    let (sink, mut chunks) = mpsc::channel(1);
+    let serialize = async move {
+        RdbWriter::new()
+            .db_to_sink(&db_snapshot, &expiry_snapshot, &sink)
+            .await
+    };
+    let forward = async {
+        while let Some(chunk) = chunks.recv().await {
+            for stream in streams.iter() {
+                let _ = stream.send(chunk.clone()).await;
+            }
+        }
+        for stream in streams.iter() {
+            let _ = stream.send(Bytes::from(mark.clone())).await;
+        }
+    };
+    let (sent, _) = tokio::join!(serialize, forward);
+    if let Ok(sent) = sent {
+        println!("Finished diskless sync of {} bytes", sent);
     }
-    false
 }
 
-async fn send_and_recieve(
-    stream: Arc<RwLock<TcpStream>>,
-    message: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let mut stream = stream.write().await;
+// Fails if the master takes longer than timeout to answer
+pub async fn send_and_recieve(
+    stream: &mut TcpStream,
+    message: &[u8],
+    timeout: Duration,
+) -> Result<String, HandshakeError> {
     // Write the message to the stream
-    stream.write_all(message.as_bytes()).await?;
+    stream.write_all(message).await?;
     stream.flush().await?;
 
     // Buffer to store the response
     let mut buf = [0; 1024];
-    let n = stream.read(&mut buf).await?;
+    let n = time::timeout(timeout, stream.read(&mut buf))
+        .await
+        .map_err(|_| "Master didn't answer during the handshake")??;
+    if n == 0 {
+        return Err("Master closed the connection during the handshake".into());
+    }
 
     // Convert the response to a String
     let response = String::from_utf8_lossy(&buf[..n]).to_string();
     Ok(response)
 }
-pub async fn perform_handshake(redis: &mut Redis) -> RespParser {
-    let ping: RespType = RespType::Array(vec![RespType::BulkString(Some(String::from("PING")))]);
+
+// Keeps the replica attached to its master, re-running the handshake with a growing delay
+// whenever the link drops or goes quiet for longer than the replication timeout
+pub async fn maintain_master_link(redis: Redis) {
+    // Read once, a change of master replaces this whole task through start_master_link
+    let master_address = {
+        let replication = redis.replication.lock().await;
+        match (&replication.master_host, &replication.master_port) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            _ => {
+                println!("No master to replicate from");
+                return;
+            }
+        }
+    };
+    let timeout = Duration::from_secs(redis.config.repl_timeout);
+    let mut reconnect_delay = MIN_RECONNECT_DELAY;
+    loop {
+        let (parser, writer) = match perform_handshake(&redis, &master_address).await {
+            Ok(x) => x,
+            Err(e) => {
+                println!(
+                    "Failed to sync with master ({}), retrying in {:?}",
+                    e, reconnect_delay
+                );
+                time::sleep(reconnect_delay).await;
+                reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+                continue;
+            }
+        };
+        reconnect_delay = MIN_RECONNECT_DELAY;
+        {
+            let mut replication = redis.replication.lock().await;
+            replication.master_link_up = true;
+            replication.touch_master_link();
+        }
+
+        let mut link = LinkGuard(redis.handle_conn(parser, writer.clone(), true));
+        let mut interval = time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                _ = &mut link.0 => break,
+                _ = interval.tick() => {
+                    let (last_io, offset) = {
+                        let replication = redis.replication.lock().await;
+                        (replication.master_last_io, replication.offset)
+                    };
+                    if last_io.is_some_and(|x| x.elapsed() > timeout) {
+                        println!("No data from master for {:?}, dropping the link", timeout);
+                        break;
+                    }
+                    // Lets the master track our progress and lag without asking for it
+                    let ack = serialize_command(&Command::ReplConf(
+                        String::from("ACK"),
+                        Some(offset.to_string()),
+                    ));
+                    writer.send(ack);
+                }
+            }
+        }
+        drop(link);
+        redis.replication.lock().await.master_link_up = false;
+        println!("Connection to master lost, reconnecting");
+    }
+}
+
+// Replaces whatever link this replica had with one to the master in the replication state
+pub async fn start_master_link(redis: &Redis) {
+    let supervisor = task::spawn(maintain_master_link(redis.clone()));
+    if let Some(previous) = redis.master_link.lock().await.replace(supervisor) {
+        previous.abort();
+    }
+}
+
+pub async fn stop_master_link(redis: &Redis) {
+    if let Some(supervisor) = redis.master_link.lock().await.take() {
+        supervisor.abort();
+    }
+}
+
+// Aborts the task processing the master's stream once the supervisor lets go of it, including
+// when the supervisor itself is aborted
+struct LinkGuard(JoinHandle<()>);
+
+impl Drop for LinkGuard {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub async fn handle_replicaof(
+    target: Option<(String, String)>,
+    output: &mut Vec<u8>,
+    redis: Redis,
+) {
+    let response = replicaof(&redis, target).await;
+    let response = serialize_resp_data(RespType::SimpleString(response));
+    output.extend_from_slice(&response);
+}
+
+// Promotes this instance when target is None, otherwise starts replicating from target.
+// Returns the status to reply with
+pub async fn replicaof(redis: &Redis, target: Option<(String, String)>) -> String {
+    // Switching roles halfway through a write would leave it applied but never propagated
+    let _write_guard = redis.write_lock.lock().await;
+    let role = redis.replication.lock().await.role;
+    match target {
+        None if role == RedisState::Master => String::from("OK"),
+        None => {
+            stop_master_link(redis).await;
+            redis.replication.lock().await.promote();
+            println!("Promoted to master");
+            String::from("OK")
+        }
+        Some((host, port)) => {
+            let mut replication = redis.replication.lock().await;
+            if role == RedisState::Replica
+                && replication.master_host.as_ref() == Some(&host)
+                && replication.master_port.as_ref() == Some(&port)
+            {
+                String::from("OK Already connected to specified master")
+            } else {
+                // Our replicas stay attached, they are only dropped if the new master can't
+                // continue our history
+                stop_master_link(redis).await;
+                println!("Replicating from {}:{}", host, port);
+                replication.demote(host, port);
+                drop(replication);
+                start_master_link(redis).await;
+                String::from("OK")
+            }
+        }
+    }
+}
+
+pub async fn handle_role(
+    output: &mut Vec<u8>,
+    replica_connections: ReplicaConnections,
+    replication: Arc<Mutex<ReplicationState>>,
+) {
+    let replication = replication.lock().await;
+    let response = match replication.role {
+        RedisState::Master => {
+            let mut replicas = vec![];
+            for replica in replica_connections.read().await.values() {
+                replicas.push(RespType::Array(vec![
+                    RespType::BulkString(Some(Bytes::from(replica.ip.clone()))),
+                    RespType::BulkString(Some(Bytes::from(replica.listening_port.clone()))),
+                    RespType::BulkString(Some(Bytes::from(replica.ack_offset.to_string()))),
+                ]));
+            }
+            RespType::Array(vec![
+                RespType::BulkString(Some(Bytes::from("master"))),
+                RespType::Integer(replication.offset as i64),
+                RespType::Array(replicas),
+            ])
+        }
+        RedisState::Replica => {
+            let state = if replication.master_link_up {
+                "connected"
+            } else {
+                "connect"
+            };
+            let port = replication
+                .master_port
+                .as_ref()
+                .and_then(|x| x.parse::<i64>().ok())
+                .unwrap_or(0);
+            RespType::Array(vec![
+                RespType::BulkString(Some(Bytes::from("slave"))),
+                RespType::BulkString(replication.master_host.clone().map(Bytes::from)),
+                RespType::Integer(port),
+                RespType::BulkString(Some(Bytes::from(state))),
+                RespType::Integer(replication.offset as i64),
+            ])
+        }
+    };
+    let response = serialize_resp_data(response);
+    output.extend_from_slice(&response);
+}
+
+pub async fn perform_handshake(
+    redis: &Redis,
+    master_address: &str,
+) -> Result<(RespParser, ConnectionWriter), HandshakeError> {
+    let ping: RespType = RespType::Array(vec![RespType::BulkString(Some(Bytes::from("PING")))]);
     let repl_port = RespType::Array(vec![
-        RespType::BulkString(Some(String::from("REPLCONF"))),
-        RespType::BulkString(Some(String::from("listening-port"))),
-        RespType::BulkString(Some(String::from(&format!("{}", redis.config.port)))),
+        RespType::BulkString(Some(Bytes::from("REPLCONF"))),
+        RespType::BulkString(Some(Bytes::from("listening-port"))),
+        RespType::BulkString(Some(Bytes::from(redis.config.port.clone()))),
     ]);
     let repl_capa = RespType::Array(vec![
-        RespType::BulkString(Some(String::from("REPLCONF"))),
-        RespType::BulkString(Some(String::from("capa"))),
-        RespType::BulkString(Some(String::from("psync2"))),
+        RespType::BulkString(Some(Bytes::from("REPLCONF"))),
+        RespType::BulkString(Some(Bytes::from("capa"))),
+        RespType::BulkString(Some(Bytes::from("psync2"))),
+    ]);
+    // Lets the master send the RDB framed by an EOF marker, see parse_rdb_file
+    let repl_capa_eof = RespType::Array(vec![
+        RespType::BulkString(Some(Bytes::from("REPLCONF"))),
+        RespType::BulkString(Some(Bytes::from("capa"))),
+        RespType::BulkString(Some(Bytes::from("eof"))),
     ]);
+    // Ask to continue from the first unprocessed byte. An instance that hasn't processed
+    // anything has no history worth continuing, so it asks for a full resync
+    let (psync_replid, psync_offset) = {
+        let replication = redis.replication.lock().await;
+        match replication.offset {
+            0 => (String::from("?"), String::from("-1")),
+            x => (replication.replid.clone(), (x + 1).to_string()),
+        }
+    };
     let psync = RespType::Array(vec![
-        RespType::BulkString(Some(String::from("PSYNC"))),
-        RespType::BulkString(Some(String::from("?"))),
-        RespType::BulkString(Some(String::from("-1"))),
+        RespType::BulkString(Some(Bytes::from("PSYNC"))),
+        RespType::BulkString(Some(Bytes::from(psync_replid))),
+        RespType::BulkString(Some(Bytes::from(psync_offset))),
     ]);
 
     let serialized_ping = serialize_resp_data(ping);
     let serialized_repl_port = serialize_resp_data(repl_port);
     let serialized_repl_capa = serialize_resp_data(repl_capa);
+    let serialized_repl_capa_eof = serialize_resp_data(repl_capa_eof);
     let serialized_psync = serialize_resp_data(psync);
-    redis.master_connection = match TcpStream::connect(format!(
-        "{}:{}",
-        redis.config.master_host.as_ref().unwrap(),
-        redis.config.master_port.as_ref().unwrap()
-    ))
-    .await
-    {
-        Ok(x) => Some(Arc::new(RwLock::new(x))),
-        Err(e) => panic!("{}", e),
-    };
-
-    let stream = Arc::clone(&redis.master_connection.as_ref().unwrap());
-    let _ = send_and_recieve(Arc::clone(&stream), &serialized_ping).await;
-    let _ = send_and_recieve(Arc::clone(&stream), &serialized_repl_port).await;
-    let _ = send_and_recieve(Arc::clone(&stream), &serialized_repl_capa).await;
-    let stream_data = send_and_recieve(Arc::clone(&stream), &serialized_psync)
+    // Applies to each step rather than the whole handshake, a large RDB may take much longer to
+    // arrive as long as the master keeps sending it
+    let timeout = Duration::from_secs(redis.config.repl_timeout);
+    let mut stream = time::timeout(timeout, TcpStream::connect(master_address))
         .await
-        .expect("Failed to recieve repl_id from master at the end of handshake");
-    // We read up to CRLF and then everything after is the contents of the RDB file
-    // if we don't read as much as we expect, we read again, until we do
-    // then the stream is empty enough
-    println!("====== Recieiving Psync Response from Master ======");
-    println!("{}", stream_data);
-    println!("====== End of Psync Response from Master ==========");
-    let mut parser = RespParser::new(stream_data, Arc::clone(&stream));
-    let resync: String;
-    let rdb: Vec<u8>;
-    (resync, rdb) = parser.parse_handshake().await;
-    parser
+        .map_err(|_| "Timed out connecting to master")??;
+
+    send_and_recieve(&mut stream, &serialized_ping, timeout).await?;
+    send_and_recieve(&mut stream, &serialized_repl_port, timeout).await?;
+    send_and_recieve(&mut stream, &serialized_repl_capa, timeout).await?;
+    send_and_recieve(&mut stream, &serialized_repl_capa_eof, timeout).await?;
+    stream.write_all(&serialized_psync).await?;
+    // The PSYNC response may be followed by the binary RDB file, so the parser reads it from
+    // the stream itself rather than through send_and_recieve
+    let (mut parser, writer) = split_connection(stream, redis.config.client_output_buffer_limit);
+    parser.set_read_timeout(Some(timeout));
+    let (resync, rdb) = parser.parse_handshake().await.ok_or(
+        "Master closed the connection, went quiet or sent a malformed reply during the handshake",
+    )?;
+    // From here on maintain_master_link watches the link for inactivity
+    parser.set_read_timeout(None);
+    println!("Master responded to PSYNC with: {}", resync);
+    let parts: Vec<&str> = resync.split(' ').collect();
+    match (parts.as_slice(), rdb) {
+        (["FULLRESYNC", replid, offset], Some(rdb)) => {
+            let offset: usize = offset.parse()?;
+            let (data_map, expiry_map) = RdbParser::new(rdb).rdb_to_db()?;
+            // Sub-replicas can't be served a snapshot while it is being replaced
+            let _write_guard = redis.write_lock.lock().await;
+            // The log still describes the data being replaced, so it is rewritten from the new
+            // data. Writes from the master that follow end up after it
+            if let Some(aof) = &redis.aof {
+                let id = aof.restart_rewrite().await;
+                persistence::spawn_aof_rewrite(
+                    Arc::clone(aof),
+                    id,
+                    data_map.clone(),
+                    expiry_map.clone(),
+                    &redis.config,
+                );
+            }
+            *redis.database.lock().await = data_map;
+            *redis.expiry.write().await = expiry_map;
+            redis
+                .replication
+                .lock()
+                .await
+                .reset(replid.to_string(), offset);
+            // Their history was discarded along with ours
+            disconnect_replicas(Arc::clone(&redis.replica_connections)).await;
+        }
+        (["CONTINUE"], None) => (),
+        (["CONTINUE", replid], None) => {
+            // The master's history was renamed, e.g. after a failover, but our offset is still valid
+            let mut replication = redis.replication.lock().await;
+            if replication.replid != *replid {
+                replication.shift_replid(replid.to_string());
+            }
+        }
+        _ => return Err(format!("Unexpected PSYNC response from master: {}", resync).into()),
+    }
+    Ok((parser, writer))
 }
@@ -0,0 +1,246 @@
+use super::connection::ConnectionWriter;
+use super::{random_u64, RedisState};
+use crate::config::Config;
+use crate::resp::RespType;
+
+use std::collections::VecDeque;
+use std::time::Instant;
+
+const NULL_REPLID: &str = "0000000000000000000000000000000000000000";
+
+// Host and port of another instance
+pub type Address = (String, String);
+
+// A replica attached to this master
+pub struct ReplicaLink {
+    pub writer: ConnectionWriter,
+    pub ip: String,
+    pub listening_port: String,
+    // Replication offset the replica last acknowledged, and when
+    pub ack_offset: usize,
+    pub last_ack: Instant,
+}
+
+// Fixed size window over the most recent bytes of the replication stream, used to catch up
+// replicas that reconnect after a short disconnection without a full resync
+pub struct ReplicationBacklog {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+}
+
+impl ReplicationBacklog {
+    pub fn new(capacity: usize) -> Self {
+        ReplicationBacklog {
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        // Only the tail of a write larger than the whole backlog can ever be served
+        let data = &data[data.len().saturating_sub(self.capacity)..];
+        let overflow = (self.buffer.len() + data.len()).saturating_sub(self.capacity);
+        self.buffer.drain(..overflow);
+        self.buffer.extend(data);
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+
+    pub fn histlen(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+pub struct ReplicationState {
+    // Role at runtime, starts out as configured and is changed by REPLICAOF
+    pub role: RedisState,
+    pub master_host: Option<String>,
+    pub master_port: Option<String>,
+    pub replid: String,
+    // Bytes of the replication stream produced as a master, or processed as a replica
+    pub offset: usize,
+    // History followed before the last promotion, replicas of the old master can still
+    // continue from it up to (excluding) second_replid_offset
+    pub replid2: String,
+    pub second_replid_offset: Option<usize>,
+    pub backlog: ReplicationBacklog,
+    // State of a replica's link to its master, unused on a master
+    pub master_link_up: bool,
+    pub master_last_io: Option<Instant>,
+    // Failover election terms, the latest one seen and the latest one this instance voted in
+    pub current_epoch: u64,
+    pub last_vote_epoch: u64,
+    // When this instance last voted for another candidate
+    pub last_vote_at: Option<Instant>,
+    // Highest offset of the same history the other replicas reported while the master was down
+    pub peer_offset: usize,
+}
+
+impl ReplicationState {
+    pub fn new(config: &Config) -> Self {
+        ReplicationState {
+            role: config.role,
+            master_host: config.master_host.clone(),
+            master_port: config.master_port.clone(),
+            replid: generate_replid(),
+            offset: 0,
+            replid2: String::from(NULL_REPLID),
+            second_replid_offset: None,
+            backlog: ReplicationBacklog::new(config.repl_backlog_size),
+            master_link_up: false,
+            master_last_io: None,
+            current_epoch: 0,
+            last_vote_epoch: 0,
+            last_vote_at: None,
+            peer_offset: 0,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.backlog.push(data);
+        self.offset += data.len();
+    }
+
+    // Start following a new replication history, e.g. after a full resync
+    pub fn reset(&mut self, replid: String, offset: usize) {
+        self.replid = replid;
+        self.offset = offset;
+        self.replid2 = String::from(NULL_REPLID);
+        self.second_replid_offset = None;
+        self.backlog.clear();
+    }
+
+    // Keeps the current history available under replid2, so replicas that followed it can
+    // still continue after the id changes, e.g. when this instance is promoted
+    pub fn shift_replid(&mut self, replid: String) {
+        self.replid2 = std::mem::replace(&mut self.replid, replid);
+        self.second_replid_offset = Some(self.offset + 1);
+    }
+
+    pub fn promote(&mut self) {
+        self.shift_replid(generate_replid());
+        self.role = RedisState::Master;
+        self.master_host = None;
+        self.master_port = None;
+        self.master_link_up = false;
+        self.master_last_io = None;
+    }
+
+    pub fn demote(&mut self, host: String, port: String) {
+        self.role = RedisState::Replica;
+        self.master_host = Some(host);
+        self.master_port = Some(port);
+        self.master_link_up = false;
+        self.master_last_io = None;
+    }
+
+    pub fn touch_master_link(&mut self) {
+        self.master_last_io = Some(Instant::now());
+    }
+
+    // Offset of the first byte still held in the backlog
+    pub fn backlog_start(&self) -> usize {
+        self.offset - self.backlog.histlen()
+    }
+
+    // Everything after the given offset, or None if that history isn't available anymore
+    pub fn bytes_since(&self, replid: &str, offset: usize) -> Option<Vec<u8>> {
+        let known_history = replid == self.replid
+            || (replid == self.replid2 && self.second_replid_offset.is_some_and(|x| offset < x));
+        if !known_history || offset < self.backlog_start() || offset > self.offset {
+            return None;
+        }
+        let skip = offset - self.backlog_start();
+        Some(self.backlog.buffer.range(skip..).copied().collect())
+    }
+}
+
+// Addresses of the replicas in a master's ROLE reply, None if it came from a replica
+pub fn parse_master_role(reply: &RespType) -> Option<Vec<Address>> {
+    let values = match reply {
+        RespType::Array(x) => x,
+        _ => return None,
+    };
+    match values.first() {
+        Some(RespType::BulkString(Some(x))) if x == "master" => (),
+        _ => return None,
+    }
+    // The role and offset are followed by the ip, port and offset of every replica
+    let replicas = match values.get(2) {
+        Some(RespType::Array(x)) => x,
+        _ => return Some(vec![]),
+    };
+    let replicas = replicas
+        .iter()
+        .filter_map(|x| match x {
+            RespType::Array(fields) => match fields.as_slice() {
+                [RespType::BulkString(Some(host)), RespType::BulkString(Some(port)), _]
+                    if !port.is_empty() =>
+                {
+                    Some((
+                        String::from_utf8_lossy(host).to_string(),
+                        String::from_utf8_lossy(port).to_string(),
+                    ))
+                }
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect();
+    Some(replicas)
+}
+
+pub fn generate_replid() -> String {
+    let mut replid = String::new();
+    while replid.len() < 40 {
+        replid.push_str(&format!("{:016x}", random_u64()));
+    }
+    replid.truncate(40);
+    replid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn bulk(x: &str) -> RespType {
+        RespType::BulkString(Some(Bytes::from(x.to_string())))
+    }
+
+    #[test]
+    fn parse_master_role_lists_replicas() {
+        let reply = RespType::Array(vec![
+            bulk("master"),
+            RespType::Integer(42),
+            RespType::Array(vec![
+                RespType::Array(vec![bulk("127.0.0.1"), bulk("6380"), bulk("42")]),
+                // Replicas that haven't announced a port can't be reached
+                RespType::Array(vec![bulk("127.0.0.1"), bulk(""), bulk("0")]),
+            ]),
+        ]);
+        assert_eq!(
+            parse_master_role(&reply),
+            Some(vec![(String::from("127.0.0.1"), String::from("6380"))])
+        );
+    }
+
+    #[test]
+    fn parse_master_role_rejects_replicas() {
+        let reply = RespType::Array(vec![
+            bulk("slave"),
+            bulk("127.0.0.1"),
+            RespType::Integer(6379),
+            bulk("connected"),
+            RespType::Integer(42),
+        ]);
+        assert_eq!(parse_master_role(&reply), None);
+        assert_eq!(parse_master_role(&RespType::Integer(1)), None);
+    }
+}
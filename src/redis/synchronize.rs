@@ -1,28 +1,95 @@
-use crate::redis::commands::Command;
+use super::commands::Command;
+use super::keyspace::Keyspace;
+use super::replication::ReplicationState;
+use super::{RedisState, ReplicaConnections};
+use crate::rdb::rdb_writer::RdbWriter;
 use crate::resp::resp_serializer::serialize_command;
+use bytes::Bytes;
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-use tokio::sync::{Mutex, RwLock};
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+use tokio::time::{self, Duration};
 
-extern crate base64;
+// Adds the data to the replication stream and sends it to every replica, returning the new
+// replication offset. Callers must hold the write lock so the stream stays in order
+pub async fn propagate_to_replicas(
+    data: &[u8],
+    replica_connections: ReplicaConnections,
+    replication: Arc<Mutex<ReplicationState>>,
+) -> usize {
+    let offset = {
+        let mut replication = replication.lock().await;
+        replication.feed(data);
+        replication.offset
+    };
+    let data = Bytes::copy_from_slice(data);
+    let mut disconnected = vec![];
+    for (id, replica) in replica_connections.read().await.iter() {
+        if !replica.writer.send(data.clone()) {
+            disconnected.push(*id);
+        }
+    }
+    // A replica that went away resyncs from the backlog when it reconnects
+    if !disconnected.is_empty() {
+        let mut connections = replica_connections.write().await;
+        for id in disconnected {
+            println!("Dropping disconnected replica {}", id);
+            connections.remove(&id);
+        }
+    }
+    offset
+}
 
-const RDB_B64: &str = "UkVESVMwMDEx+glyZWRpcy12ZXIFNy4yLjD6CnJlZGlzLWJpdHPAQPoFY3RpbWXCbQi8ZfoIdXNlZC1tZW3CsMQQAPoIYW9mLWJhc2XAAP/wbjv+wP9aog==";
+// A replica's offset counts every byte processed from its master, PINGs and commands it failed
+// to run included. The bytes are passed on to sub-replicas verbatim so their offsets match ours.
+// Callers must hold the write lock, like for propagate_to_replicas
+pub async fn account_master_stream(
+    data: &[u8],
+    replica_connections: ReplicaConnections,
+    replication: Arc<Mutex<ReplicationState>>,
+) {
+    propagate_to_replicas(data, replica_connections, Arc::clone(&replication)).await;
+    replication.lock().await.touch_master_link();
+}
 
-pub async fn propagate_command_to_replica(stream: Arc<RwLock<TcpStream>>, command: &Command) {
-    let serialized_command = serialize_command(command);
-    let mut stream = stream.write().await;
-    if let Err(e) = stream.write_all(serialized_command.as_bytes()).await {
-        println!("Failed to write to stream: {}", e);
+// Closes every replication link, the replicas reconnect and resync against the current history
+pub async fn disconnect_replicas(replica_connections: ReplicaConnections) {
+    for (_id, replica) in replica_connections.write().await.drain() {
+        replica.writer.shutdown();
     }
-    if let Err(e) = stream.flush().await {
-        println!("Failed to flush stream: {}", e);
+}
+
+// Keeps idle replication links alive so replicas can tell a quiet master from a dead one. A
+// replica stays quiet, its sub-replicas already get the PINGs of its own master
+pub async fn ping_replicas(
+    period: Duration,
+    replica_connections: ReplicaConnections,
+    replication: Arc<Mutex<ReplicationState>>,
+    write_lock: Arc<Mutex<()>>,
+) {
+    let ping = serialize_command(&Command::Ping);
+    let mut interval = time::interval(period);
+    loop {
+        interval.tick().await;
+        let is_master = replication.lock().await.role == RedisState::Master;
+        if is_master && !replica_connections.read().await.is_empty() {
+            let _write_guard = write_lock.lock().await;
+            propagate_to_replicas(
+                &ping,
+                Arc::clone(&replica_connections),
+                Arc::clone(&replication),
+            )
+            .await;
+        }
     }
 }
 
-pub fn construct_rdb(_database: Arc<Mutex<HashMap<String, String>>>) -> (String, Vec<u8>) {
-    let binary_data = base64::decode(RDB_B64).expect("Failed to decode base64");
+pub fn construct_rdb(
+    database: &Keyspace,
+    expiry: &HashMap<Bytes, SystemTime>,
+) -> (String, Vec<u8>) {
+    let binary_data = RdbWriter::new().db_to_rdb(database, expiry);
     let length = binary_data.len();
     (format!("${}\r\n", length), binary_data)
 }
@@ -1,11 +1,33 @@
 pub mod resp_deserializer;
 pub mod resp_serializer;
 
+use bytes::Bytes;
+
 #[derive(Debug)]
 pub enum RespType {
     Integer(i64),
     SimpleString(String),
     Error(String),
-    BulkString(Option<String>),
+    // Binary safe, keys and values are arbitrary bytes
+    BulkString(Option<Bytes>),
     Array(Vec<RespType>),
+    // RESP3 only, see resp_serializer::downgrade_to_resp2 for how RESP2 connections see them
+    Null,
+    // RESP2's null array, which RESP3 connections are sent as a Null instead
+    NullArray,
+    Boolean(bool),
+    Double(f64),
+    BigNumber(String),
+    // Three letter format such as txt or mkd, followed by the text itself
+    VerbatimString(String, Bytes),
+    Map(Vec<(RespType, RespType)>),
+    Set(Vec<RespType>),
+    Push(Vec<RespType>),
+}
+
+// Negotiated per connection with HELLO, every connection starts on RESP2
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Protocol {
+    Resp2,
+    Resp3,
 }
@@ -1,15 +1,23 @@
 use super::RespType;
-use crate::redis::commands::{self, Command};
+use crate::redis::commands::{self, Command, CommandError};
 
-use std::sync::Arc;
+use bytes::{Bytes, BytesMut};
 use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
-use tokio::sync::RwLock;
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::time::{self, Duration};
+
+// Large enough for a sizeable pipeline to arrive in one read
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+// Longest inline command accepted, like Redis's PROTO_INLINE_MAX_SIZE
+const INLINE_MAX_SIZE: usize = 64 * 1024;
 
 pub struct RespParser {
-    data: String,
-    index: usize,
-    stream: Arc<RwLock<TcpStream>>,
+    stream: OwnedReadHalf,
+    // Bytes read off the stream but not parsed yet. Nothing is decoded as text, so binary values
+    // and the RDB sent during a full resync come out exactly as they were sent
+    buffer: BytesMut,
+    // Longest a single read may wait for data, None to wait indefinitely
+    read_timeout: Option<Duration>,
 }
 
 impl RespParser {
@@ -17,228 +25,499 @@ impl RespParser {
     // |                                         |
     // -------------------------------------------
 
-    pub fn new(data: String, stream: Arc<RwLock<TcpStream>>) -> RespParser {
+    pub fn new(stream: OwnedReadHalf) -> RespParser {
         RespParser {
-            data,
-            index: 0,
             stream,
+            buffer: BytesMut::with_capacity(READ_CHUNK_SIZE),
+            read_timeout: None,
         }
     }
 
-    pub async fn parse_command(&mut self) -> Option<(Command, usize)> {
-        assert!(self.index == 0);
-        let num_args = match self.find_num_args_in_array().await {
-            Some(x) => x,
-            None => return None,
-        };
+    pub fn set_read_timeout(&mut self, read_timeout: Option<Duration>) {
+        self.read_timeout = read_timeout;
+    }
 
-        if !self.validate_array_length(num_args).await {
-            return None;
+    // Returns the command along with the exact bytes it was parsed from, reading from the stream
+    // until a whole one has arrived. None once the connection is closed
+    pub async fn parse_command(&mut self) -> Option<(Result<Command, CommandError>, Bytes)> {
+        loop {
+            if let Some(x) = self.parse_buffered_command() {
+                return Some(x);
+            }
+            self.read_from_stream().await?;
         }
-        let data_before_processing = self.data.clone();
+    }
 
-        // Assume command_name will always be provided as a bulk string
-        let command_name = self.parse_bulk_string();
-        let mut command_data: Vec<RespType> = Vec::new();
-        for _ in 1..=(num_args - 1) {
-            if self.index > self.data.len() {
-                panic!("Index is >= length of data while parsing arguments");
-            }
-            match self.data.chars().nth(self.index).unwrap() {
-                '$' => command_data.push(self.parse_bulk_string()),
-                '+' => command_data.push(self.parse_bulk_string()),
-                other @ _ => panic!("Unsupported RESP data type encountered: {}", other),
+    // Like parse_command, but only looks at what has already been read. None if that doesn't
+    // hold a whole command. Anything that doesn't start as an array is taken as an inline command,
+    // so a node can be used from netcat or telnet. After a protocol error the rest of the buffer
+    // can't be framed and is dropped
+    pub fn parse_buffered_command(&mut self) -> Option<(Result<Command, CommandError>, Bytes)> {
+        loop {
+            let parsed = match self.buffer.first() {
+                Some(b'*') | None => parse_value(&self.buffer, 0),
+                Some(_) => parse_inline(&self.buffer),
+            };
+            let (value, length) = match parsed {
+                Ok(x) => x?,
+                Err(e) => {
+                    self.buffer.clear();
+                    return Some((Err(e), Bytes::new()));
+                }
+            };
+            let raw = self.buffer.split_to(length).freeze();
+            // Blank lines are skipped rather than treated as a command
+            if matches!(&value, RespType::Array(x) if x.is_empty()) {
+                continue;
             }
+            return Some((value_to_command(value), raw));
         }
-        let command = match command_name {
-            RespType::BulkString(x) => commands::args_to_command(&x.unwrap(), command_data),
-            _ => panic!("Expected Command Name to be provided as a bulk string"),
-        };
-        self.reset_data();
-        let bytes_processed = data_before_processing.len() - self.data.len();
-        return Some((command, bytes_processed));
     }
 
-    pub async fn parse_handshake(&mut self) -> (String, Vec<u8>) {
-        assert!(self.index == 0);
-        // First parse the simple string
-        while !self.data.contains("\r\n") {
-            self.read_data_from_stream().await;
+    // Reads a whole reply of any type, for connections on which this node is the client. None
+    // once the connection is closed
+    pub async fn parse_reply(&mut self) -> Option<Result<RespType, CommandError>> {
+        loop {
+            match parse_value(&self.buffer, 0) {
+                Ok(Some((value, length))) => {
+                    let _ = self.buffer.split_to(length);
+                    return Some(Ok(value));
+                }
+                Ok(None) => self.read_from_stream().await?,
+                Err(e) => return Some(Err(e)),
+            };
         }
-        let simple = self.parse_simple_string();
-        self.reset_data();
+    }
 
-        let resync = match simple {
-            RespType::SimpleString(x) => x,
-            _ => panic!("Expected parse_simple_string to return a simple string"),
+    // Returns the PSYNC response and, for a full resync, the RDB file that follows it.
+    // None if the master closed the connection partway through
+    pub async fn parse_handshake(&mut self) -> Option<(String, Option<Vec<u8>>)> {
+        // Masters send newlines to keep the link alive until the transfer starts
+        loop {
+            let newlines = self.buffer.iter().take_while(|x| **x == b'\n').count();
+            let _ = self.buffer.split_to(newlines);
+            if !self.buffer.is_empty() {
+                break;
+            }
+            self.read_from_stream().await?;
+        }
+        let line = self.read_line().await?;
+        let resync = match line.strip_prefix('+') {
+            Some(x) => x.to_string(),
+            None => {
+                println!(
+                    "Expected a simple string in response to PSYNC, got: {}",
+                    line
+                );
+                return None;
+            }
         };
-        let rdb = self.parse_rdb_file().await;
-        self.reset_data();
-        println!("Data after parsing RDB: {}", self.data);
-        println!("Length of data after parsing RDB: {}", self.data.len());
-        (resync, rdb)
+        // A partial resync continues straight into the replication stream
+        let rdb = if resync.starts_with("FULLRESYNC") {
+            Some(self.parse_rdb_file().await?)
+        } else {
+            None
+        };
+        // Whatever follows the RDB file is the start of the replication stream and stays buffered
+        Some((resync, rdb))
     }
 
     // ----------------- Private -----------------
     // |                                         |
     // -------------------------------------------
 
-    async fn read_data_from_stream(&mut self) -> Option<usize> {
-        let mut stream = self.stream.write().await;
-        let mut buffer: [u8; 1024] = [0; 1024];
-        match stream.read(&mut buffer).await {
-            // This should return none in future, panic is for debugging
-            Ok(0) => panic!("Stream was closed"),
-            Ok(bytes_read) => {
-                let valid_data = &buffer[..bytes_read];
-                let string_data = String::from_utf8_lossy(valid_data).to_string();
-                self.data += &string_data;
-                println!("========Recieved New Transmission========");
-                println!("{}", string_data);
-                println!("========End of New Transmission========");
-                Some(bytes_read)
+    // None once the other side has closed the connection, it has failed or the read timed out
+    async fn read_from_stream(&mut self) -> Option<usize> {
+        self.buffer.reserve(READ_CHUNK_SIZE);
+        let read = self.stream.read_buf(&mut self.buffer);
+        let result = match self.read_timeout {
+            Some(timeout) => match time::timeout(timeout, read).await {
+                Ok(x) => x,
+                Err(_) => {
+                    println!("No data received for {:?}", timeout);
+                    return None;
+                }
+            },
+            None => read.await,
+        };
+        match result {
+            Ok(0) => None,
+            Ok(bytes_read) => Some(bytes_read),
+            Err(e) => {
+                println!("Error reading from stream: {}", e);
+                None
             }
-            Err(_) => {
-                // In future, this function should return a result type
-                panic!("Error reading from stream");
+        }
+    }
+
+    async fn read_line(&mut self) -> Option<String> {
+        loop {
+            if let Some(crlf_index) = find_crlf(&self.buffer, 0) {
+                let line = self.buffer.split_to(crlf_index + 2);
+                return Some(String::from_utf8_lossy(&line[..crlf_index]).to_string());
             }
+            self.read_from_stream().await?;
         }
     }
 
-    async fn parse_rdb_file(&mut self) -> Vec<u8> {
-        while !self.data.contains("\r\n") {
-            self.read_data_from_stream().await;
+    async fn read_bytes(&mut self, length: usize) -> Option<Vec<u8>> {
+        while self.buffer.len() < length {
+            self.read_from_stream().await?;
         }
-        if !self.check_next_substring("$") {
-            panic!("Expected bulk string indicator byte before RDB file");
+        Some(self.buffer.split_to(length).to_vec())
+    }
+
+    // Returns everything before the marker and drops the marker itself
+    async fn read_until(&mut self, mark: &[u8]) -> Option<Vec<u8>> {
+        let mut searched = 0;
+        loop {
+            if let Some(index) = self.buffer[searched..]
+                .windows(mark.len())
+                .position(|x| x == mark)
+            {
+                let data = self.buffer.split_to(searched + index).to_vec();
+                let _ = self.buffer.split_to(mark.len());
+                return Some(data);
+            }
+            // The marker may straddle what has been read so far
+            searched = self.buffer.len().saturating_sub(mark.len() - 1);
+            self.read_from_stream().await?;
         }
-        self.index += 1;
-        let length: usize = self
-            .read_to_crlf()
-            .parse()
-            .expect("Failed to convert RDB length to usize");
-        let mut remainder = &self.data[self.index..];
+    }
 
+    async fn parse_rdb_file(&mut self) -> Option<Vec<u8>> {
+        let header = self.read_line().await?;
+        // A diskless transfer doesn't know the length up front and ends with the marker instead
+        if let Some(mark) = header.strip_prefix("$EOF:") {
+            return self.read_until(mark.as_bytes()).await;
+        }
+        // Anything else fails the handshake, which is then retried from scratch
+        let length: usize = match header.strip_prefix('$').map(|x| x.parse()) {
+            Some(Ok(x)) => x,
+            _ => {
+                println!("Expected the length of the RDB file, got: {}", header);
+                return None;
+            }
+        };
         println!("Length of RDB: {}", length);
-        println!("Length of remainder: {}", remainder.len());
-        // Now we must read the next x chars
-        // TODO: Stop hardcoding RDB size
-        let num_bytes: usize = 120;
-        while remainder.len() < length {
-            self.read_data_from_stream().await;
-            remainder = &self.data[self.index..];
-        }
-
-        let rdb = remainder[0..num_bytes].as_bytes().to_vec();
-        self.index += num_bytes;
-        rdb
-    }
-
-    // Function assumes the data has been reset and leads with an asterisk
-    async fn find_num_args_in_array(&mut self) -> Option<usize> {
-        let mut remainder = self.data[self.index..].to_owned();
-        while !remainder.contains("\r\n") {
-            self.read_data_from_stream().await;
-            remainder = self.data[self.index..].to_owned();
-        }
-        if !self.check_next_substring("*") {
-            panic!("Expected first character to be * while finding num args");
-        }
-        self.index += 1;
-        let num_args: i32 = self
-            .read_to_crlf()
-            .parse()
-            .expect("Could not parse num args into usize");
-        if num_args <= 0 {
-            panic!("Expected num_args to be > 0");
-        }
-        Some(num_args as usize)
-    }
-
-    async fn validate_array_length(&mut self, num_args: usize) -> bool {
-        if num_args == 0 {
-            panic!("Expected num_args to be greater than zero");
-        }
-        let mut num_crlfs = num_args;
-        let mut remainder = self.data[self.index..].to_owned();
-        let mut validating_index: usize = self.index;
-        // Index might be greater than length after parsing num_args
-        while num_crlfs > 0 || self.index >= self.data.len() {
-            if self.check_next_substring("*") {
-                match self.find_num_args_in_array().await {
-                    Some(x) => num_crlfs += x,
-                    None => return false,
-                }
+        // Unlike a bulk string the RDB file isn't followed by a CRLF
+        self.read_bytes(length).await
+    }
+}
+
+// Parses the value starting at index, returning it along with the index right after it. None if
+// the data ends before the value does
+fn parse_value(data: &[u8], index: usize) -> Result<Option<(RespType, usize)>, CommandError> {
+    let crlf_index = match find_crlf(data, index) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let (type_byte, header) = match data[index..crlf_index].split_first() {
+        Some(x) => x,
+        None => return Err(protocol_error("expected a type byte, got an empty line")),
+    };
+    let index = crlf_index + 2;
+    match type_byte {
+        b'+' => Ok(Some((
+            RespType::SimpleString(String::from_utf8_lossy(header).to_string()),
+            index,
+        ))),
+        b'-' => Ok(Some((
+            RespType::Error(String::from_utf8_lossy(header).to_string()),
+            index,
+        ))),
+        b':' => Ok(Some((
+            RespType::Integer(parse_number(header, "invalid integer")?),
+            index,
+        ))),
+        b'$' => {
+            let length = parse_number(header, "invalid bulk length")?;
+            if length == -1 {
+                return Ok(Some((RespType::BulkString(None), index)));
+            } else if length < 0 {
+                return Err(protocol_error("invalid bulk length"));
+            }
+            let end = index + length as usize;
+            if data.len() < end + 2 {
+                return Ok(None);
             }
-            if let Some(x) = remainder.find("\r\n") {
-                num_crlfs -= 1;
-                validating_index += x + 2;
-                remainder = self.data[validating_index..].to_owned();
-            } else {
-                self.read_data_from_stream().await;
+            if &data[end..end + 2] != b"\r\n" {
+                return Err(protocol_error("bulk string is longer than its length"));
             }
+            let bulk_string = Bytes::copy_from_slice(&data[index..end]);
+            Ok(Some((RespType::BulkString(Some(bulk_string)), end + 2)))
         }
-        true
-    }
-
-    fn parse_simple_string(&mut self) -> RespType {
-        if !self.check_next_substring("+") {
-            panic!("Failed to find simple string indicator byte (+)");
+        b'*' => {
+            let length = parse_number(header, "invalid multibulk length")?;
+            if length < 0 {
+                return Err(protocol_error("invalid multibulk length"));
+            }
+            let mut index = index;
+            let mut values = Vec::new();
+            for _ in 0..length {
+                let (value, next_index) = match parse_value(data, index)? {
+                    Some(x) => x,
+                    None => return Ok(None),
+                };
+                values.push(value);
+                index = next_index;
+            }
+            Ok(Some((RespType::Array(values), index)))
         }
-        self.index += 1;
-        let simple_string = self.read_to_crlf();
-        RespType::SimpleString(simple_string)
+        other => Err(protocol_error(&format!(
+            "unsupported type byte '{}'",
+            *other as char
+        ))),
     }
+}
+
+// Parses a line of space separated arguments, returning them as an array of bulk strings along
+// with the index right after the line. None if the line hasn't been fully read yet
+fn parse_inline(data: &[u8]) -> Result<Option<(RespType, usize)>, CommandError> {
+    // Without a cap a client that never sends a newline would grow the buffer forever
+    let newline_index = match data.iter().position(|x| *x == b'\n') {
+        Some(x) if x <= INLINE_MAX_SIZE => x,
+        None if data.len() <= INLINE_MAX_SIZE => return Ok(None),
+        _ => return Err(protocol_error("too big inline request")),
+    };
+    let line = match data[..newline_index].strip_suffix(b"\r") {
+        Some(x) => x,
+        None => &data[..newline_index],
+    };
+    let args = match split_inline_args(line) {
+        Some(x) => x,
+        None => return Err(protocol_error("unbalanced quotes in request")),
+    };
+    let args = args
+        .into_iter()
+        .map(|x| RespType::BulkString(Some(Bytes::from(x))))
+        .collect();
+    Ok(Some((RespType::Array(args), newline_index + 1)))
+}
 
-    fn parse_bulk_string(&mut self) -> RespType {
-        if self.data.chars().nth(self.index).unwrap() != '$' {
-            panic!("Failed to find bulk string indicator byte $");
+// Splits the way redis-cli does: double quoted arguments understand escapes like \n and \x41,
+// single quoted ones only \'. None if a quote is left open or isn't followed by a space
+fn split_inline_args(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut args = vec![];
+    let mut index = 0;
+    loop {
+        while index < line.len() && line[index].is_ascii_whitespace() {
+            index += 1;
         }
-        self.index += 1;
-        let length: i32 = self
-            .read_to_crlf()
-            .parse()
-            .expect("Could not convert Bulk String length to i32");
-        if length == -1 {
-            return RespType::BulkString(None);
-        } else if length <= 0 {
-            panic!("Expected bulk string length to be greater than 0");
+        if index == line.len() {
+            return Some(args);
+        }
+        let mut arg = vec![];
+        match line[index] {
+            b'"' => {
+                index += 1;
+                loop {
+                    match *line.get(index)? {
+                        b'\\'
+                            if index + 3 < line.len()
+                                && line[index + 1] == b'x'
+                                && line[index + 2].is_ascii_hexdigit()
+                                && line[index + 3].is_ascii_hexdigit() =>
+                        {
+                            let hex = std::str::from_utf8(&line[index + 2..index + 4]).ok()?;
+                            arg.push(u8::from_str_radix(hex, 16).ok()?);
+                            index += 4;
+                        }
+                        b'\\' => {
+                            arg.push(match *line.get(index + 1)? {
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                b'b' => 0x08,
+                                b'a' => 0x07,
+                                other => other,
+                            });
+                            index += 2;
+                        }
+                        b'"' => {
+                            index += 1;
+                            break;
+                        }
+                        other => {
+                            arg.push(other);
+                            index += 1;
+                        }
+                    }
+                }
+            }
+            b'\'' => {
+                index += 1;
+                loop {
+                    match *line.get(index)? {
+                        b'\\' if line.get(index + 1) == Some(&b'\'') => {
+                            arg.push(b'\'');
+                            index += 2;
+                        }
+                        b'\'' => {
+                            index += 1;
+                            break;
+                        }
+                        other => {
+                            arg.push(other);
+                            index += 1;
+                        }
+                    }
+                }
+            }
+            _ => {
+                while index < line.len() && !line[index].is_ascii_whitespace() {
+                    arg.push(line[index]);
+                    index += 1;
+                }
+            }
         }
-        let bulk_string = self.read_to_crlf();
-        if bulk_string.len() != length as usize {
-            panic!(
-                "{}",
-                format!(
-                    "Bulk string length: {} did not match provided length: {}",
-                    bulk_string.len(),
-                    length
-                )
-            );
+        // A closing quote has to end the argument
+        if index < line.len() && !line[index].is_ascii_whitespace() {
+            return None;
         }
-        RespType::BulkString(Some(bulk_string))
+        args.push(arg);
+    }
+}
+
+fn value_to_command(value: RespType) -> Result<Command, CommandError> {
+    let mut args = match value {
+        RespType::Array(x) if !x.is_empty() => x,
+        _ => return Err(protocol_error("expected a command as a non-empty array")),
+    };
+    // Clients have to send every part of a command as a bulk string
+    if !args
+        .iter()
+        .all(|x| matches!(x, RespType::BulkString(Some(_))))
+    {
+        return Err(protocol_error(
+            "expected every argument to be a bulk string",
+        ));
+    }
+    let command_name = match args.remove(0) {
+        RespType::BulkString(Some(x)) => String::from_utf8_lossy(&x).to_string(),
+        _ => unreachable!(),
+    };
+    commands::args_to_command(&command_name, args)
+}
+
+fn find_crlf(data: &[u8], index: usize) -> Option<usize> {
+    data[index..]
+        .windows(2)
+        .position(|x| x == b"\r\n")
+        .map(|x| index + x)
+}
+
+fn parse_number(header: &[u8], message: &str) -> Result<i64, CommandError> {
+    std::str::from_utf8(header)
+        .ok()
+        .and_then(|x| x.parse::<i64>().ok())
+        .ok_or_else(|| protocol_error(message))
+}
+
+fn protocol_error(message: &str) -> CommandError {
+    CommandError::Protocol(String::from(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(line: &str) -> Option<Vec<String>> {
+        split_inline_args(line.as_bytes()).map(|args| {
+            args.into_iter()
+                .map(|x| String::from_utf8_lossy(&x).to_string())
+                .collect()
+        })
     }
 
-    fn check_next_substring(&self, sequence: &str) -> bool {
-        let remainder = &self.data[self.index..];
-        if sequence.len() > remainder.len() {
-            return false;
-        } else if &remainder[self.index..(self.index + sequence.len())] == sequence {
-            return true;
+    fn inline_args(data: &[u8]) -> Vec<Vec<u8>> {
+        match parse_inline(data) {
+            Ok(Some((RespType::Array(args), _))) => args
+                .into_iter()
+                .map(|x| match x {
+                    RespType::BulkString(Some(x)) => x.to_vec(),
+                    other => panic!("Expected a bulk string, got {:?}", other),
+                })
+                .collect(),
+            other => panic!("Expected a parsed line, got {:?}", other),
         }
-        false
     }
 
-    fn reset_data(&mut self) {
-        self.data = self.data[self.index..].to_string();
-        self.index = 0;
+    #[test]
+    fn splits_on_any_whitespace() {
+        assert_eq!(
+            split("  SET  key\tvalue "),
+            Some(vec!["SET".into(), "key".into(), "value".into()])
+        );
+        assert_eq!(split("   "), Some(vec![]));
+    }
+
+    #[test]
+    fn double_quotes_understand_escapes() {
+        assert_eq!(split(r#""\x41\x62c""#), Some(vec!["Abc".into()]));
+        assert_eq!(split(r#""a\nb\t\"c\"""#), Some(vec!["a\nb\t\"c\"".into()]));
+        // Not followed by two hex digits, so only the x is kept
+        assert_eq!(split(r#""\xZZ""#), Some(vec!["xZZ".into()]));
+        assert_eq!(split(r#""" x"#), Some(vec!["".into(), "x".into()]));
+    }
+
+    #[test]
+    fn single_quotes_only_escape_quotes() {
+        assert_eq!(
+            split(r"'it\'s' '\n'"),
+            Some(vec!["it's".into(), r"\n".into()])
+        );
+    }
+
+    #[test]
+    fn rejects_unbalanced_quotes() {
+        assert_eq!(split(r#"SET "key value"#), None);
+        assert_eq!(split("SET 'key"), None);
+        assert_eq!(split(r#"SET "key\""#), None);
+    }
+
+    #[test]
+    fn rejects_closing_quote_followed_by_non_space() {
+        assert_eq!(split(r#"SET "key"value"#), None);
+        assert_eq!(split("SET 'key'value"), None);
+        assert_eq!(
+            split(r#"SET "key" value"#),
+            Some(vec!["SET".into(), "key".into(), "value".into()])
+        );
+    }
+
+    #[test]
+    fn inline_waits_for_the_newline() {
+        assert!(matches!(parse_inline(b"PING"), Ok(None)));
+        assert_eq!(inline_args(b"PING\r\nECHO"), vec![b"PING".to_vec()]);
+        assert_eq!(
+            inline_args(b"ECHO hi\n"),
+            vec![b"ECHO".to_vec(), b"hi".to_vec()]
+        );
+        assert!(matches!(parse_inline(b"PING\r\nECHO"), Ok(Some((_, 6)))));
+    }
+
+    #[test]
+    fn inline_reports_unbalanced_quotes() {
+        assert!(matches!(
+            parse_inline(b"ECHO \"hi\r\n"),
+            Err(CommandError::Protocol(_))
+        ));
     }
 
-    fn read_to_crlf(&mut self) -> String {
-        let remainder = &self.data[self.index..];
-        let crlf_index: usize = remainder
-            .find("\r\n")
-            .expect("Could not find CRLF while reading to CRLF");
-        self.index += crlf_index + 2;
-        remainder[0..crlf_index].to_owned()
+    #[test]
+    fn inline_lines_are_capped() {
+        let mut line = vec![b'a'; INLINE_MAX_SIZE];
+        assert!(matches!(parse_inline(&line), Ok(None)));
+        line.push(b'a');
+        assert!(matches!(
+            parse_inline(&line),
+            Err(CommandError::Protocol(_))
+        ));
+        line.push(b'\n');
+        assert!(matches!(
+            parse_inline(&line),
+            Err(CommandError::Protocol(_))
+        ));
     }
 }
@@ -1,68 +1,315 @@
-use super::RespType;
-use crate::redis::commands::Command;
+use super::{Protocol, RespType};
+use crate::redis::commands::{Command, ListEnd, SetExpiry};
 
-fn serialize_bulk_string(data: String) -> String {
-    let str_len = data.len();
-    String::from(&format!("${}\r\n{}\r\n", str_len, data))
+use bytes::Bytes;
+
+fn serialize_bulk_string(data: Option<Bytes>) -> Vec<u8> {
+    match data {
+        Some(data) => {
+            let mut serialized = format!("${}\r\n", data.len()).into_bytes();
+            serialized.extend_from_slice(&data);
+            serialized.extend_from_slice(b"\r\n");
+            serialized
+        }
+        None => create_null_string(),
+    }
+}
+
+fn serialize_simple_string(data: String) -> Vec<u8> {
+    format!("+{}\r\n", data).into_bytes()
+}
+
+fn serialize_error(data: String) -> Vec<u8> {
+    format!("-{}\r\n", data).into_bytes()
 }
 
-fn serialize_simple_string(data: String) -> String {
-    String::from(&format!("+{}\r\n", data))
+fn serialize_integer(data: i64) -> Vec<u8> {
+    format!(":{}\r\n", data).into_bytes()
 }
 
-fn serialize_integer(data: i64) -> String {
-    String::from(&format!(":{}\r\n", data))
+fn serialize_array(data: Vec<RespType>) -> Vec<u8> {
+    serialize_aggregate('*', data)
 }
 
-fn serialize_array(data: Vec<RespType>) -> String {
-    let length = data.len();
-    let mut serialized = String::from(&format!("*{}\r\n", length));
+fn serialize_aggregate(type_byte: char, data: Vec<RespType>) -> Vec<u8> {
+    let mut serialized = format!("{}{}\r\n", type_byte, data.len()).into_bytes();
     for x in data {
-        serialized.push_str(&serialize_resp_data(x));
+        serialized.extend_from_slice(&serialize_resp_data(x));
+    }
+    serialized
+}
+
+fn serialize_map(data: Vec<(RespType, RespType)>) -> Vec<u8> {
+    let mut serialized = format!("%{}\r\n", data.len()).into_bytes();
+    for (key, value) in data {
+        serialized.extend_from_slice(&serialize_resp_data(key));
+        serialized.extend_from_slice(&serialize_resp_data(value));
     }
     serialized
 }
 
-pub fn serialize_resp_data(data: RespType) -> String {
-    let serialized = match data {
-        RespType::BulkString(x) => serialize_bulk_string(x.as_ref().unwrap().to_string()),
+fn serialize_verbatim_string(format: String, data: Bytes) -> Vec<u8> {
+    let length = format.len() + 1 + data.len();
+    let mut serialized = format!("={}\r\n{}:", length, format).into_bytes();
+    serialized.extend_from_slice(&data);
+    serialized.extend_from_slice(b"\r\n");
+    serialized
+}
+
+fn format_double(data: f64) -> String {
+    if data.is_nan() {
+        String::from("nan")
+    } else if data.is_infinite() {
+        String::from(if data > 0.0 { "inf" } else { "-inf" })
+    } else {
+        data.to_string()
+    }
+}
+
+// Serializes the value exactly as given, RESP3 types included
+pub fn serialize_resp_data(data: RespType) -> Vec<u8> {
+    match data {
+        RespType::BulkString(x) => serialize_bulk_string(x),
         RespType::Array(x) => serialize_array(x),
-        RespType::SimpleString(x) => serialize_simple_string(x.to_string()),
+        RespType::SimpleString(x) => serialize_simple_string(x),
         RespType::Integer(x) => serialize_integer(x),
-        other @ _ => panic!("Serialization isn't support for {:?}", other),
-    };
-    serialized
+        RespType::Error(x) => serialize_error(x),
+        RespType::Null => b"_\r\n".to_vec(),
+        RespType::NullArray => b"*-1\r\n".to_vec(),
+        RespType::Boolean(x) => format!("#{}\r\n", if x { 't' } else { 'f' }).into_bytes(),
+        RespType::Double(x) => format!(",{}\r\n", format_double(x)).into_bytes(),
+        RespType::BigNumber(x) => format!("({}\r\n", x).into_bytes(),
+        RespType::VerbatimString(format, x) => serialize_verbatim_string(format, x),
+        RespType::Map(x) => serialize_map(x),
+        RespType::Set(x) => serialize_aggregate('~', x),
+        RespType::Push(x) => serialize_aggregate('>', x),
+    }
+}
+
+// Serializes a reply in the protocol the connection negotiated
+pub fn serialize_for_protocol(data: RespType, protocol: Protocol) -> Vec<u8> {
+    match protocol {
+        Protocol::Resp2 => serialize_resp_data(downgrade_to_resp2(data)),
+        // Only ever a whole reply, never nested
+        Protocol::Resp3 if matches!(data, RespType::NullArray) => {
+            serialize_resp_data(RespType::Null)
+        }
+        Protocol::Resp3 => serialize_resp_data(data),
+    }
 }
 
-pub fn create_null_string() -> String {
-    String::from("$-1\r\n")
+// Maps every RESP3 type onto the closest RESP2 one, the same way Redis answers RESP2 clients
+pub fn downgrade_to_resp2(data: RespType) -> RespType {
+    match data {
+        RespType::Null => RespType::BulkString(None),
+        RespType::Boolean(x) => RespType::Integer(x as i64),
+        RespType::Double(x) => RespType::BulkString(Some(Bytes::from(format_double(x)))),
+        RespType::BigNumber(x) => RespType::BulkString(Some(Bytes::from(x))),
+        RespType::VerbatimString(_, x) => RespType::BulkString(Some(x)),
+        RespType::Map(x) => RespType::Array(
+            x.into_iter()
+                .flat_map(|(key, value)| [downgrade_to_resp2(key), downgrade_to_resp2(value)])
+                .collect(),
+        ),
+        RespType::Array(x) | RespType::Set(x) | RespType::Push(x) => {
+            RespType::Array(x.into_iter().map(downgrade_to_resp2).collect())
+        }
+        other => other,
+    }
+}
+
+pub fn create_null_string() -> Vec<u8> {
+    b"$-1\r\n".to_vec()
+}
+
+// Turns a parsed command back into the RESP array a client would send for it, which is how
+// writes are passed on to replicas and the AOF
+pub fn serialize_command(command: &Command) -> Vec<u8> {
+    let args: Vec<RespType> = command_to_args(command)
+        .into_iter()
+        .map(|x| RespType::BulkString(Some(x)))
+        .collect();
+    serialize_resp_data(RespType::Array(args))
 }
 
-// TODO: Eventually I should be able to use this function for all commands
-pub fn serialize_command(command: &Command) -> String {
+fn command_to_args(command: &Command) -> Vec<Bytes> {
     match command {
-        Command::Set(key, value, expiry) => {
-            let mut serialized: Vec<RespType> = vec![
-                RespType::BulkString(Some(String::from("SET"))),
-                RespType::BulkString(Some(String::from(format!("{}", key)))),
-                RespType::BulkString(Some(String::from(format!("{}", value)))),
-            ];
-            if let Some(x) = expiry {
-                serialized.push(RespType::BulkString(Some(String::from(format!("{}", x)))));
+        Command::Ping => vec![Bytes::from("PING")],
+        Command::Echo(message) => vec![Bytes::from("ECHO"), message.clone()],
+        Command::Set(key, value, lifespan) => {
+            let mut args = vec![Bytes::from("SET"), key.clone(), value.clone()];
+            match lifespan {
+                Some(SetExpiry::After(x)) => {
+                    args.push(Bytes::from("PX"));
+                    args.push(Bytes::from(x.to_string()));
+                }
+                Some(SetExpiry::At(x)) => {
+                    args.push(Bytes::from("PXAT"));
+                    args.push(Bytes::from(x.to_string()));
+                }
+                None => (),
             }
-            let x = serialize_resp_data(RespType::Array(serialized));
-            x
+            args
+        }
+        Command::Get(key) => vec![Bytes::from("GET"), key.clone()],
+        Command::Type(key) => vec![Bytes::from("TYPE"), key.clone()],
+        Command::Info(section) => vec![Bytes::from("INFO"), Bytes::from(section.clone())],
+        Command::ReplConf(arg1, arg2) => {
+            let mut args = vec![Bytes::from("REPLCONF"), Bytes::from(arg1.clone())];
+            args.extend(arg2.clone().map(Bytes::from));
+            args
+        }
+        Command::Psync(replid, offset) => {
+            vec![
+                Bytes::from("PSYNC"),
+                Bytes::from(replid.clone()),
+                Bytes::from(offset.clone()),
+            ]
         }
-        Command::ReplConf(arg1, arg2_optional) => {
-            let mut serialized: Vec<RespType> = vec![
-                RespType::BulkString(Some(String::from("REPLCONF"))),
-                RespType::BulkString(Some(arg1.to_owned())),
+        Command::Wait(replicas, timeout) => vec![
+            Bytes::from("WAIT"),
+            Bytes::from(replicas.to_string()),
+            Bytes::from(timeout.to_string()),
+        ],
+        Command::ConfigGet(parameter) => vec![
+            Bytes::from("CONFIG"),
+            Bytes::from("GET"),
+            Bytes::from(parameter.clone()),
+        ],
+        Command::Keys(pattern) => vec![Bytes::from("KEYS"), Bytes::from(pattern.clone())],
+        Command::Save => vec![Bytes::from("SAVE")],
+        Command::BgSave => vec![Bytes::from("BGSAVE")],
+        Command::LastSave => vec![Bytes::from("LASTSAVE")],
+        Command::BgRewriteAof => vec![Bytes::from("BGREWRITEAOF")],
+        Command::ReplicaOf(Some((host, port))) => {
+            vec![
+                Bytes::from("REPLICAOF"),
+                Bytes::from(host.clone()),
+                Bytes::from(port.clone()),
+            ]
+        }
+        Command::ReplicaOf(None) => vec![
+            Bytes::from("REPLICAOF"),
+            Bytes::from("NO"),
+            Bytes::from("ONE"),
+        ],
+        Command::Role => vec![Bytes::from("ROLE")],
+        Command::RequestVote(epoch, replid, offset) => vec![
+            Bytes::from("REQUESTVOTE"),
+            Bytes::from(epoch.to_string()),
+            Bytes::from(replid.clone()),
+            Bytes::from(offset.to_string()),
+        ],
+        Command::Sentinel(subcommand, args) => {
+            let mut all_args = vec![Bytes::from("SENTINEL"), Bytes::from(subcommand.clone())];
+            all_args.extend(args.iter().cloned().map(Bytes::from));
+            all_args
+        }
+        Command::Hello(protocol_version, auth, client_name) => {
+            let mut args = vec![Bytes::from("HELLO")];
+            args.extend(protocol_version.map(|x| Bytes::from(x.to_string())));
+            if let Some((username, password)) = auth {
+                args.push(Bytes::from("AUTH"));
+                args.push(Bytes::from(username.clone()));
+                args.push(Bytes::from(password.clone()));
+            }
+            if let Some(name) = client_name {
+                args.push(Bytes::from("SETNAME"));
+                args.push(Bytes::from(name.clone()));
+            }
+            args
+        }
+        Command::Del(keys) => {
+            let mut args = vec![Bytes::from("DEL")];
+            args.extend(keys.iter().cloned());
+            args
+        }
+        Command::LPush(key, elements) => {
+            let mut args = vec![Bytes::from("LPUSH"), key.clone()];
+            args.extend(elements.iter().cloned());
+            args
+        }
+        Command::RPush(key, elements) => {
+            let mut args = vec![Bytes::from("RPUSH"), key.clone()];
+            args.extend(elements.iter().cloned());
+            args
+        }
+        Command::LPop(key, count) => {
+            let mut args = vec![Bytes::from("LPOP"), key.clone()];
+            args.extend(count.map(|x| Bytes::from(x.to_string())));
+            args
+        }
+        Command::RPop(key, count) => {
+            let mut args = vec![Bytes::from("RPOP"), key.clone()];
+            args.extend(count.map(|x| Bytes::from(x.to_string())));
+            args
+        }
+        Command::LLen(key) => vec![Bytes::from("LLEN"), key.clone()],
+        Command::LRange(key, start, stop) => vec![
+            Bytes::from("LRANGE"),
+            key.clone(),
+            Bytes::from(start.to_string()),
+            Bytes::from(stop.to_string()),
+        ],
+        Command::LIndex(key, index) => vec![
+            Bytes::from("LINDEX"),
+            key.clone(),
+            Bytes::from(index.to_string()),
+        ],
+        Command::LSet(key, index, element) => vec![
+            Bytes::from("LSET"),
+            key.clone(),
+            Bytes::from(index.to_string()),
+            element.clone(),
+        ],
+        Command::LRem(key, count, element) => vec![
+            Bytes::from("LREM"),
+            key.clone(),
+            Bytes::from(count.to_string()),
+            element.clone(),
+        ],
+        Command::LTrim(key, start, stop) => vec![
+            Bytes::from("LTRIM"),
+            key.clone(),
+            Bytes::from(start.to_string()),
+            Bytes::from(stop.to_string()),
+        ],
+        Command::LInsert(key, before, pivot, element) => vec![
+            Bytes::from("LINSERT"),
+            key.clone(),
+            Bytes::from(if *before { "BEFORE" } else { "AFTER" }),
+            pivot.clone(),
+            element.clone(),
+        ],
+        Command::LPos(key, element, rank, count, maxlen) => {
+            let mut args = vec![
+                Bytes::from("LPOS"),
+                key.clone(),
+                element.clone(),
+                Bytes::from("RANK"),
+                Bytes::from(rank.to_string()),
             ];
-            if let Some(arg2) = arg2_optional {
-                serialized.push(RespType::BulkString(Some(String::from(arg2))));
-            };
-            serialize_resp_data(RespType::Array(serialized))
+            if let Some(x) = count {
+                args.push(Bytes::from("COUNT"));
+                args.push(Bytes::from(x.to_string()));
+            }
+            args.push(Bytes::from("MAXLEN"));
+            args.push(Bytes::from(maxlen.to_string()));
+            args
         }
-        other @ _ => panic!("Serialization unsupported for {:?}", other),
+        Command::LMove(source, destination, from, to) => vec![
+            Bytes::from("LMOVE"),
+            source.clone(),
+            destination.clone(),
+            list_end_to_arg(*from),
+            list_end_to_arg(*to),
+        ],
+    }
+}
+
+fn list_end_to_arg(end: ListEnd) -> Bytes {
+    match end {
+        ListEnd::Left => Bytes::from("LEFT"),
+        ListEnd::Right => Bytes::from("RIGHT"),
     }
 }
@@ -0,0 +1,378 @@
+use crate::config::Config;
+use crate::redis::commands::{Command, CommandError};
+use crate::redis::connection::split_connection;
+use crate::redis::failover::{info_replication, query};
+use crate::redis::replication::{generate_replid, parse_master_role, Address};
+use crate::resp::{
+    resp_serializer::{create_null_string, serialize_resp_data},
+    RespType,
+};
+
+use bytes::Bytes;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::task;
+use tokio::time::{self, Duration, Instant};
+
+const CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+struct SentinelState {
+    master_name: String,
+    master: Address,
+    quorum: usize,
+    // Replicas of the master as it last reported them
+    replicas: Vec<Address>,
+    // Former masters that have to be pointed at the current one once they are back
+    demoted: Vec<Address>,
+    // Last time the master answered a PING
+    master_last_ok: Instant,
+    current_epoch: u64,
+    // The sentinel this one voted for to lead the failover in leader_epoch
+    leader: Option<String>,
+    leader_epoch: u64,
+    last_failover_attempt: Option<Instant>,
+}
+
+impl SentinelState {
+    fn master_down(&self, down_after: Duration) -> bool {
+        self.master_last_ok.elapsed() > down_after
+    }
+}
+
+// Watches a master and its replicas, agrees with its peers when the master is down and
+// promotes a replica in its place. Clients ask it for the current master's address
+#[derive(Clone)]
+pub struct Sentinel {
+    config: Arc<Config>,
+    listener: Arc<TcpListener>,
+    // Identifies this sentinel in leader elections
+    runid: String,
+    state: Arc<Mutex<SentinelState>>,
+}
+
+impl Sentinel {
+    pub fn new(config: Arc<Config>, listener: TcpListener) -> Self {
+        let monitor = config
+            .sentinel_monitor
+            .as_ref()
+            .expect("Sentinel mode requires --sentinel-monitor");
+        let state = SentinelState {
+            master_name: monitor.name.clone(),
+            master: (monitor.host.clone(), monitor.port.clone()),
+            quorum: monitor.quorum,
+            replicas: vec![],
+            demoted: vec![],
+            master_last_ok: Instant::now(),
+            current_epoch: 0,
+            leader: None,
+            leader_epoch: 0,
+            last_failover_attempt: None,
+        };
+        Sentinel {
+            config,
+            listener: Arc::new(listener),
+            runid: generate_replid(),
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    pub async fn listen(&mut self) -> Result<Self, Box<dyn std::error::Error + 'static>> {
+        task::spawn(monitor(self.clone()));
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            println!("New stream connected to sentinel: {:?}", stream);
+            self.handle_conn(stream);
+        }
+    }
+
+    fn handle_conn(&self, stream: TcpStream) {
+        let sentinel = self.clone();
+        task::spawn(async move {
+            let (mut parser, writer) =
+                split_connection(stream, sentinel.config.client_output_buffer_limit);
+            while let Some((command, _raw)) = parser.parse_command().await {
+                let command = match command {
+                    Ok(x) => x,
+                    Err(error) => {
+                        let is_protocol_error = matches!(error, CommandError::Protocol(_));
+                        let response = serialize_resp_data(RespType::Error(error.to_string()));
+                        writer.send(response);
+                        if is_protocol_error {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                let response = match command {
+                    Command::Ping => {
+                        serialize_resp_data(RespType::SimpleString(String::from("PONG")))
+                    }
+                    Command::Sentinel(subcommand, args) => {
+                        sentinel.handle_sentinel(subcommand, args).await
+                    }
+                    _ => serialize_resp_data(RespType::Error(String::from(
+                        "ERR command not available in sentinel mode",
+                    ))),
+                };
+                writer.send(response);
+            }
+        });
+    }
+
+    async fn handle_sentinel(&self, subcommand: String, args: Vec<String>) -> Vec<u8> {
+        let down_after = Duration::from_millis(self.config.sentinel_down_after);
+        let mut state = self.state.lock().await;
+        match (subcommand.to_lowercase().as_str(), args.as_slice()) {
+            ("get-master-addr-by-name", [name]) => {
+                if *name != state.master_name {
+                    return create_null_string();
+                }
+                serialize_resp_data(RespType::Array(vec![
+                    RespType::BulkString(Some(Bytes::from(state.master.0.clone()))),
+                    RespType::BulkString(Some(Bytes::from(state.master.1.clone()))),
+                ]))
+            }
+            ("is-master-down-by-addr", [host, port, epoch, runid]) => {
+                let epoch = match epoch.parse::<u64>() {
+                    Ok(x) => x,
+                    Err(_) => {
+                        return serialize_resp_data(RespType::Error(String::from(
+                            "ERR invalid epoch",
+                        )))
+                    }
+                };
+                let down =
+                    state.master == (host.clone(), port.clone()) && state.master_down(down_after);
+                // A runid instead of * asks for our vote, which goes to the first asker per epoch
+                if down && runid != "*" && epoch > state.leader_epoch {
+                    state.leader = Some(runid.clone());
+                    state.leader_epoch = epoch;
+                    state.current_epoch = state.current_epoch.max(epoch);
+                }
+                serialize_resp_data(RespType::Array(vec![
+                    RespType::Integer(down as i64),
+                    RespType::BulkString(Some(Bytes::from(
+                        state.leader.clone().unwrap_or_else(|| String::from("*")),
+                    ))),
+                    RespType::Integer(state.leader_epoch as i64),
+                ]))
+            }
+            _ => serialize_resp_data(RespType::Error(String::from(
+                "ERR unknown SENTINEL subcommand or wrong number of arguments",
+            ))),
+        }
+    }
+
+    // Points former masters that came back at the current master
+    async fn reconfigure_demoted(&self) {
+        let (master, demoted) = {
+            let state = self.state.lock().await;
+            (state.master.clone(), state.demoted.clone())
+        };
+        for address in demoted {
+            let info = match info_replication(&address).await {
+                Some(x) => x,
+                None => continue,
+            };
+            let following = info.get("role").map(String::as_str) == Some("slave")
+                && info.get("master_host") == Some(&master.0)
+                && info.get("master_port") == Some(&master.1);
+            if following {
+                self.state.lock().await.demoted.retain(|x| *x != address);
+                continue;
+            }
+            println!("+convert-to-slave {}:{}", address.0, address.1);
+            let _ = query(&address, &["REPLICAOF", &master.0, &master.1]).await;
+        }
+    }
+
+    // Adopts a replica another sentinel has already promoted, true if one was found
+    async fn follow_promoted_replica(&self) -> bool {
+        let replicas = self.state.lock().await.replicas.clone();
+        for replica in replicas {
+            let info = match info_replication(&replica).await {
+                Some(x) => x,
+                None => continue,
+            };
+            if info.get("role").map(String::as_str) == Some("master") {
+                self.switch_master(replica).await;
+                return true;
+            }
+        }
+        false
+    }
+
+    async fn try_failover(&self) {
+        let failover_timeout = Duration::from_millis(self.config.failover_timeout);
+        let (master, quorum) = {
+            let mut state = self.state.lock().await;
+            if state
+                .last_failover_attempt
+                .is_some_and(|x| x.elapsed() < failover_timeout)
+            {
+                return;
+            }
+            state.last_failover_attempt = Some(Instant::now());
+            (state.master.clone(), state.quorum)
+        };
+        let peers = &self.config.sentinel_peers;
+
+        // Objectively down once enough sentinels agree
+        let mut down_votes = 1;
+        for peer in peers {
+            let reply = self.ask_is_master_down(peer, &master, 0, "*").await;
+            if reply.first().map(String::as_str) == Some("1") {
+                down_votes += 1;
+            }
+        }
+        if down_votes < quorum {
+            return;
+        }
+        println!(
+            "+odown master {}:{} #quorum {}/{}",
+            master.0, master.1, down_votes, quorum
+        );
+
+        let epoch = {
+            let mut state = self.state.lock().await;
+            state.current_epoch += 1;
+            state.leader = Some(self.runid.clone());
+            state.leader_epoch = state.current_epoch;
+            state.current_epoch
+        };
+        let mut votes = 1;
+        for peer in peers {
+            let reply = self
+                .ask_is_master_down(peer, &master, epoch, &self.runid)
+                .await;
+            if reply.get(1) == Some(&self.runid) && reply.get(2) == Some(&epoch.to_string()) {
+                votes += 1;
+            }
+        }
+        let sentinels = peers.len() + 1;
+        let majority = sentinels / 2 + 1;
+        if votes < quorum.max(majority) {
+            println!("-failover-not-elected epoch {} with {} votes", epoch, votes);
+            return;
+        }
+        println!("+elected-leader epoch {}", epoch);
+        self.promote_best_replica().await;
+    }
+
+    // Promotes the reachable replica with the highest replication offset and points the
+    // others at it
+    async fn promote_best_replica(&self) {
+        let replicas = self.state.lock().await.replicas.clone();
+        let mut best: Option<(Address, usize)> = None;
+        for replica in replicas.iter() {
+            let info = match info_replication(replica).await {
+                Some(x) if x.get("role").map(String::as_str) == Some("slave") => x,
+                _ => continue,
+            };
+            let offset = info
+                .get("master_repl_offset")
+                .and_then(|x| x.parse::<usize>().ok())
+                .unwrap_or(0);
+            if best.as_ref().is_none_or(|(_, x)| offset > *x) {
+                best = Some((replica.clone(), offset));
+            }
+        }
+        let promoted = match best {
+            Some((address, _)) => address,
+            None => {
+                println!("-failover-abort-no-good-slave");
+                return;
+            }
+        };
+        match query(&promoted, &["REPLICAOF", "NO", "ONE"]).await {
+            Ok(RespType::SimpleString(x)) if x == "OK" => (),
+            _ => {
+                println!(
+                    "-failover-abort-slave-timeout {}:{}",
+                    promoted.0, promoted.1
+                );
+                return;
+            }
+        }
+        for replica in replicas.iter().filter(|x| **x != promoted) {
+            let _ = query(replica, &["REPLICAOF", &promoted.0, &promoted.1]).await;
+        }
+        self.switch_master(promoted).await;
+    }
+
+    async fn switch_master(&self, promoted: Address) {
+        let mut state = self.state.lock().await;
+        println!(
+            "+switch-master {} {} {} {} {}",
+            state.master_name, state.master.0, state.master.1, promoted.0, promoted.1
+        );
+        let old_master = std::mem::replace(&mut state.master, promoted.clone());
+        if !state.demoted.contains(&old_master) {
+            state.demoted.push(old_master);
+        }
+        state.replicas.retain(|x| *x != promoted);
+        state.master_last_ok = Instant::now();
+    }
+
+    async fn ask_is_master_down(
+        &self,
+        peer: &Address,
+        master: &Address,
+        epoch: u64,
+        runid: &str,
+    ) -> Vec<String> {
+        let epoch = epoch.to_string();
+        let request = [
+            "SENTINEL",
+            "is-master-down-by-addr",
+            &master.0,
+            &master.1,
+            &epoch,
+            runid,
+        ];
+        match query(peer, &request).await {
+            Ok(reply) => reply_values(&reply),
+            Err(_) => vec![],
+        }
+    }
+}
+
+async fn monitor(sentinel: Sentinel) {
+    let down_after = Duration::from_millis(sentinel.config.sentinel_down_after);
+    let mut interval = time::interval(CHECK_PERIOD);
+    loop {
+        interval.tick().await;
+        let master = sentinel.state.lock().await.master.clone();
+        if let Ok(RespType::SimpleString(x)) = query(&master, &["PING"]).await {
+            if x == "PONG" {
+                sentinel.state.lock().await.master_last_ok = Instant::now();
+            }
+        }
+        let role = query(&master, &["ROLE"]).await;
+        if let Some(replicas) = role.ok().as_ref().and_then(parse_master_role) {
+            sentinel.state.lock().await.replicas = replicas;
+        }
+        sentinel.reconfigure_demoted().await;
+
+        if !sentinel.state.lock().await.master_down(down_after) {
+            continue;
+        }
+        if sentinel.follow_promoted_replica().await {
+            continue;
+        }
+        sentinel.try_failover().await;
+    }
+}
+
+// Every value of a reply in order with arrays flattened, enough for the replies sentinels
+// exchange
+fn reply_values(reply: &RespType) -> Vec<String> {
+    match reply {
+        RespType::Array(x) => x.iter().flat_map(reply_values).collect(),
+        RespType::BulkString(Some(x)) => vec![String::from_utf8_lossy(x).to_string()],
+        RespType::SimpleString(x) => vec![x.clone()],
+        RespType::Integer(x) => vec![x.to_string()],
+        _ => vec![],
+    }
+}